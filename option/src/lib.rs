@@ -1,4 +1,4 @@
-//! This contract demonstrates 'equity cash settled put option' concept
+//! This contract demonstrates 'equity cash settled put and call option' concept
 //! and implements a contract similar to a Claimable Balance (similar to
 //! https://developers.stellar.org/docs/glossary/claimable-balance).
 //! The contract allows a buyer and seller to deposit some amount of collateral
@@ -8,27 +8,22 @@
 //! commensurate with the trade price and maximum risk.  Both the buyer and seller
 //! can claim the collateral after the expiration time adjusted for the final
 //! settlement price, when the oracle has provided the price of the underlying
-//! asset.
+//! asset.  Open positions can be transferred to a new holder before the
+//! expiration.  American options can also be exercised by the buyer at any time
+//! before expiration against a regular session price from the oracle.  If the
+//! opposing party never deposits, the first party can withdraw its collateral
+//! once the match window of the series has passed.  Positions are netted per
+//! account, collateral that is no longer needed to cover a short position is
+//! released as soon as an account's position is reduced.  Each series moves
+//! through an explicit lifecycle, see State, and publishes every change.
 //!
-//! TODO: Add features such as:
-//! American options allowing the buyer to exercise the option at any time
-//! before expiration.
-//! Trade history.
-//! Trading of options.  Keep track of positions by Address.
-//! Trade netting to allow buyer and sellers to adjust balances based on
-//! positions.
-//! Allow the withdrawal of collateral if the opposing party has not
-//! deposited collateral within a certain time period.
-//!
-//! ** trade function is broken, need to fix **
-//! 
 #![no_std]
 #![allow(dead_code)]
 
 #[contract]
 struct OptionContract;
 
-use soroban_sdk::{contract, contractimpl, contracttype, token, Address, Env, Symbol, Vec};
+use soroban_sdk::{contract, contracterror, contractimpl, contracttype, token, Address, Env, Symbol, Vec};
 
 mod oracle {
     soroban_sdk::contractimport!(
@@ -40,43 +35,74 @@ mod oracle {
 const SIDE_SELL: u32 = 0;
 const SIDE_BUY: u32 = 1;
 
-const AMERICAN: u32 = 1; // American option, can be exercised at any time before expiration, not supported at this time
+const AMERICAN: u32 = 1; // American option, can be exercised at any time before expiration, puts and calls only
 const EUROPEAN: u32 = 2; // European option, can only be exercised at expiration
 const CALL: u32 = 4; // Call option
 const PUT: u32 = 8; // Put option
-const BINARY: u32 = 16; // Binary option, either 0 or 1
+const BINARY: u32 = 16; // Binary option, cash or nothing, pays either 0 or the fixed payout
 const CALL_SPRD: u32 = 32; // Basic call spread, long call at low strike, short call at high strike
 const PUT_SPRD: u32 = 64; // Basic put spread, long put at low strike, short put at high strike
+const BUTTERFLY: u32 = 128; // Butterfly, three strikes, pays the most at the middle strike
+const CONDOR: u32 = 256; // Condor, four strikes, pays the most outside the outer strikes
+
+// Oracle flags, see toSorobanArgs() in pxpump.js
+const ORACLE_REGULAR: i128 = 4; // Regular market session price
+const ORACLE_SETTLE: i128 = 32; // Official close, the settlement price
+
+const MAX_STALENESS: u64 = 3600; // Default oldest an oracle quote can be, in seconds
+
+// Errors returned by the contract functions, the codes are stable so off-chain
+// clients can map them to their own messages.  The error code of this protocol
+// version only has room for codes up to 9, so related failures share a code.
+#[contracterror]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, PartialOrd, Ord)]
+#[repr(u32)]
+pub enum Error {
+    NotInitialized = 1,   // init has not been called
+    InvalidInput = 2,     // Unknown series, trade or account, or listing or trade details out of range
+    DecimalsMismatch = 3, // Decimals don't match the series
+    OracleFee = 4,        // The caller could not pay the oracle's fee for a price
+    DepositExists = 5,    // The side has already traded
+    Expired = 6,          // Past the expiration
+    InvalidState = 7,     // Not allowed where the series or the account is in its life
+    OracleNotFinal = 8,   // No fresh price, or no settlement or regular session price, from the oracle
+    NegativePayout = 9,   // Payout would be negative or more than the series balance
+}
 
 // These are the variables that are stored in the contract storage. We want to minimize the number of
-// storage variables to minimize the cost of the contract.  We use a single storage variable to store
-// all the option details.  The option details are stored as a vector of bytes.  The vector is
-// serialized and deserialized using the soroban_sdk::storage::StorageValue trait.
+// storage variables to minimize the cost of the contract.  Each listed option series gets an id and
+// its OptionDef is stored under a single persistent entry, DataKey::Series(id).  The trade and
+// oracle details of a series are keyed by the same id so many strikes and expirations can live
+// in one contract instance.
 
 #[derive(Clone)]
 #[contracttype]
 pub enum DataKey {
-    Init,         // Initialization flag
-    BAdr,         // Buyer address
-    SAdr,         // Seller address
-    SDep,         // Seller deposit
-    BDep,         // Buyer deposit
-    Balance,      // Balance of the contract
-    Strike,       // Strike price of the option, in terms of the collateral token
-    MktPrice,     // Market price of the underlying asset in terms of the collateral token
-    Expiration,   // Expiration time of the option, Unix timestamp in milliseconds
-    Oracle,       // Oracle contract address
-    Token,        // Collateral Token contract address
-    Trds,         // Trade history, only for the initial buyer/seller at this point
-    Admin,        // Option Smart Contract Admin address
-    TradePx,      // Trade price
-    TradeQty,     // Trade quantity
-    OptionType,   // Option type, bitmask for option details
-    OracleTs,     // Latest update from the Oracle's timestamp
-    OracleFlags,  // Oracle flags, bitmask for update details
-    OracleSymbol, // Oracle Symbol, the underlying asset symbol in some normalized standard format *See SYMBOLOGY.md for details
-    TradeId,      // Trade ID
-    Decimals,     // Number of decimals for the price and strike
+    Init,              // Initialization flag
+    SeriesId,          // Id of the most recently listed option series
+    Series(u64),       // Option series definition, OptionDef
+    BAdr(u64),         // Buyer address
+    SAdr(u64),         // Seller address
+    SDep(u64),         // Seller deposit
+    BDep(u64),         // Buyer deposit
+    Balance(u64),      // Balance of the series
+    MktPrice(u64),     // Market price of the underlying asset in terms of the collateral token
+    Trd(u64, u32),     // Trade at an index of the series' trade history
+    NTrds(u64),        // Number of trades in the trade history
+    TrdIdx(u64, u64),  // Index of a matched trade in the trade history, by trade id
+    TradePx(u64),      // Trade price
+    TradeQty(u64),     // Trade quantity
+    OracleTs(u64),     // Latest update from the Oracle's timestamp
+    OracleFlags(u64),  // Oracle flags, bitmask for update details
+    OracleSymbol(u64), // Oracle Symbol, the underlying asset symbol in some normalized standard format *See SYMBOLOGY.md for details
+    TradeId(u64),      // Trade ID
+    State(u64),        // Where the series is in its life, see State
+    MatchExp(u64),     // Time by which the second side has to trade, Unix timestamp
+    Pos(u64, Address), // Position of an account in the series
+    Acct(u64, u32),    // Account at an index of the accounts that have held a position in the series
+    NAccts(u64),       // Number of accounts that have held a position in the series
+    Coll(u64, Address), // Collateral an account has in the series
+    Claimed(u64, Address), // Set once an account has settled the series
 }
 
 #[derive(Clone)]
@@ -102,30 +128,87 @@ pub struct Trade {
     pub qty: i128,       // Quantity of the trade
     pub buyer: Address,  // Buyer address
     pub seller: Address, // Seller address
-    pub date_time: u64,  // Date and time of the trade, Unix timestamp in milliseconds
+    pub date_time: u64,  // Date and time of the trade, ledger timestamp
     pub trade_id: u64,   // Trade ID
 }
 
 #[derive(Clone)]
 #[contracttype]
 pub struct Position {
-    pub pos: i128,
-    pub acct: Address,
-    pub token: Address,
+    pub pos: i128,      // Net position in the series, long > 0, short < 0
+    pub price: i128,    // Average price the open position was traded at, in terms of the collateral token
+    pub acct: Address,  // Account holding the position
+    pub token: Address, // Collateral token of the series
 }
 
 #[derive(Clone)]
 #[contracttype]
 pub struct OptionDef {
-    pub collateral_token: Address,
-    pub underlying_token: Address,
-    pub underlying_symbol: Symbol,
-    pub strike: i128,
-    pub mkt_price: i128,
-    pub exp: TimeBound,
-    pub opt_type: u32, // Bitmask for options details 0x1 = American, 0x2 = European, 0x4 = Call, 0x8 = Put, 0xF = Binary,...
-    pub symbol: Symbol,
-    pub decimals: u32,
+    pub collateral_token: Address, // Collateral Token contract address
+    pub symbol: Symbol,            // Underlying asset symbol *See SYMBOLOGY.md for details
+    pub oracle: Address,           // Oracle contract address
+    pub admin: Address,            // Admin address that listed the series
+    pub strikes: Vec<i128>,        // Strike prices of the option legs in ascending order, in terms of the collateral token, a call's second strike is its cap
+    pub exp: TimeBound,            // Expiration time of the option
+    pub opt_type: u32, // Bitmask for options details 0x1 = American, 0x2 = European, 0x4 = Call, 0x8 = Put, 0x10 = Binary,...
+    pub decimals: u32, // Number of decimals for the price and strike
+    pub payout: i128,  // Fixed payout of a binary option, 0 for other option types
+    pub match_window: u64, // Time the second side has to trade after the first side, in seconds
+    pub max_staleness: u64, // Oldest an oracle quote can be, in seconds, see is_fresh()
+}
+
+// Where a series is in its life.  Every change is published as a
+// ("state", series) event with the new state.
+//   Listed -> HalfFilled -> Matched -> Expired -> SettlementPriced -> Settled
+//   HalfFilled -> Cancelled -> HalfFilled, when the first side cancels
+//   Listed, HalfFilled or Cancelled -> Expired, when nothing was matched
+// Expiring is published by the first upd_px(), mtm() or cancel_unmatched()
+// past the expiration, specs() and info() report Expired before that.  A
+// call that fails publishes nothing since its changes are rolled back.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[contracttype]
+pub enum State {
+    Listed,           // Listed, nobody has traded yet
+    HalfFilled,       // One side has traded, waiting for the other side
+    Matched,          // Both sides have traded
+    Expired,          // Past the expiration, no settlement price yet
+    SettlementPriced, // The oracle settlement price is in, accounts can settle
+    Settled,          // Every account has been paid out
+    Cancelled,        // The first side took its deposit back, can be traded again
+}
+
+// A side of the primary trade.  This stands in for an Option<Address> field,
+// which builds for the contract but not with the SDK's testutils: this SDK
+// version has no ScVal conversion or SorobanArbitrary for Option.
+#[derive(Clone, PartialEq, Eq, Debug)]
+#[contracttype]
+pub enum Party {
+    None,          // The side has not traded
+    Acct(Address), // The account that traded the side
+}
+
+// The trade and oracle details of a series
+#[derive(Clone)]
+#[contracttype]
+pub struct Info {
+    pub buyer: Party,       // Buyer of the primary trade
+    pub seller: Party,      // Seller of the primary trade
+    pub buyer_dep: i128,    // Buyer deposit
+    pub seller_dep: i128,   // Seller deposit
+    pub balance: i128,      // Collateral token held for the series
+    pub mkt_price: i128,    // Last oracle price of the underlying asset
+    pub oracle_ts: i128,    // Timestamp of the last oracle price
+    pub oracle_flags: i128, // Flags of the last oracle price
+    pub trade_id: u64,      // Trade id of the primary trade
+    pub state: State,       // Where the series is in its life
+}
+
+// The option details of a series and where it is in its life
+#[derive(Clone)]
+#[contracttype]
+pub struct Specs {
+    pub def: OptionDef,
+    pub state: State,
 }
 
 // The 'timelock' part: check that provided timestamp is before/after
@@ -140,10 +223,12 @@ fn check_time_bound(env: &Env, time_bound: &TimeBound) -> bool {
 }
 
 // Contract usage:
-// 1. Initialize the contract with the option details.
-// 2. Buyer and seller deposit the required amount of token.
-// 3. Buyer and seller can claim the balance after the expiration time.
-// 4. Buyer and seller can claim the balance after the oracle has provided
+// 1. Initialize the contract.
+// 2. The admin lists an option series with the option details and gets
+//    back a series id.  Any number of series can be listed.
+// 3. Buyer and seller deposit the required amount of token for a series.
+// 4. Buyer and seller can claim the balance after the expiration time.
+// 5. Buyer and seller can claim the balance after the oracle has provided
 //    the price of the underlying asset, and the price is above/below the
 //    strike price.
 
@@ -153,18 +238,22 @@ impl OptionContract {
         env.storage().instance().set(&DataKey::Init, &true);
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn list(
         env: Env,
-        opt_type: u32,      // option type, only put option is supported at this time
-        strike: i128,       // strike price
-        decimals: u32,       // number of decimals for the strike price
+        opt_type: u32,      // option type, American put or call, or European put, call, binary, spread, butterfly or condor
+        strikes: Vec<i128>, // strike prices, one per leg in ascending order, a call's strike then its cap
+        decimals: u32,       // number of decimals for the strike prices
         exp: u64,           // expiration date and time
         oracle: Address,    // oracle contract address
         token: Address,     // token address (e.g. USDC)
         admin: Address,     // admin address
-    ) {
+        payout: i128,       // fixed payout for binary options, 0 otherwise
+        match_window: u64,  // time the second side has to trade after the first side
+        symbol: Symbol,     // underlying asset symbol
+    ) -> Result<u64, Error> {
         if !is_initialized(&env) {
-            panic!("contract is not initialized");
+            return Err(Error::NotInitialized);
         }
 
         // TODO: Should we let anyone list an option in the future? 
@@ -181,16 +270,39 @@ impl OptionContract {
             timestamp: exp,
         };
         // Set the option details
-        if opt_type != (PUT | EUROPEAN)  {
-            panic!("only put option is supported at this time");
+        if opt_type != (PUT | EUROPEAN)
+            && opt_type != (CALL | EUROPEAN)
+            && opt_type != (PUT | AMERICAN)
+            && opt_type != (CALL | AMERICAN)
+            && opt_type != (CALL_SPRD | EUROPEAN)
+            && opt_type != (PUT_SPRD | EUROPEAN)
+            && opt_type != (BUTTERFLY | EUROPEAN)
+            && opt_type != (CONDOR | EUROPEAN)
+            && opt_type != (BINARY | CALL | EUROPEAN)
+            && opt_type != (BINARY | PUT | EUROPEAN)
+        {
+            return Err(Error::InvalidInput);
         }
         
         // Do some checking on the input parameters
-        if strike <= 0  {
-            panic!("strike price must be greater than 0");
+        if strikes.len() != strike_count(opt_type) {
+            return Err(Error::InvalidInput);
+        }
+        let mut prev_strike: i128 = 0;
+        for strike in strikes.iter() {
+            if strike <= prev_strike {
+                return Err(Error::InvalidInput);
+            }
+            prev_strike = strike;
+        }
+        if opt_type & BINARY != 0 && payout <= 0 {
+            return Err(Error::InvalidInput);
+        }
+        if opt_type & BINARY == 0 && payout != 0 {
+            return Err(Error::InvalidInput);
         }
         if exp <= env.ledger().timestamp()  {
-            panic!("expiration time must be in the future");
+            return Err(Error::InvalidInput);
         }
 
         // if oracle == Address::default()  {
@@ -203,33 +315,92 @@ impl OptionContract {
         //     panic!("admin address must be provided");
         // }
 
+        // Each listing gets the next series id
+        let series: u64 = env.storage().instance().get(&DataKey::SeriesId).unwrap_or(0) + 1;
+        env.storage().instance().set(&DataKey::SeriesId, &series);
+
+        let def = OptionDef {
+            collateral_token: token,
+            symbol,
+            oracle,
+            admin,
+            strikes,
+            exp: e,
+            opt_type,
+            decimals,
+            payout,
+            match_window,
+            max_staleness: MAX_STALENESS,
+        };
+
         // // Set the option details
-        env.storage().instance().set(&DataKey::Init, &true);
-        env.storage().instance().set(&DataKey::OptionType, &opt_type);
-        env.storage().instance().set(&DataKey::Strike, &strike);
-        env.storage().instance().set(&DataKey::Expiration, &e);
-        env.storage().instance().set(&DataKey::Oracle, &oracle);
-        env.storage().instance().set(&DataKey::Token, &token);
-        env.storage().instance().set(&DataKey::SDep, &0);
-        env.storage().instance().set(&DataKey::BDep, &0);
-        env.storage().instance().set(&DataKey::Balance, &0);
-        env.storage().instance().set(&DataKey::MktPrice, &0);
-        env.storage().instance().set(&DataKey::OracleTs, &0);
-        env.storage().instance().set(&DataKey::OracleFlags, &0);
-        env.storage().instance().set(&DataKey::TradeId, &0);
-        env.storage().instance().set(&DataKey::Admin, &admin);
-        env.storage().instance().set(&DataKey::Decimals, &decimals);
-    }
-
-    // Return the option details
-    pub fn specs() {}
+        env.storage().persistent().set(&DataKey::Series(series), &def);
+        env.storage().persistent().set(&DataKey::SDep(series), &0_i128);
+        env.storage().persistent().set(&DataKey::BDep(series), &0_i128);
+        env.storage().persistent().set(&DataKey::Balance(series), &0_i128);
+        env.storage().persistent().set(&DataKey::MktPrice(series), &0_i128);
+        env.storage().persistent().set(&DataKey::OracleTs(series), &0_i128);
+        env.storage().persistent().set(&DataKey::OracleFlags(series), &0_i128);
+        env.storage().persistent().set(&DataKey::TradeId(series), &0_u64);
+        set_state(&env, series, State::Listed);
+
+        Ok(series)
+    }
+
+    // Return the option details of a series and its listing status, so
+    // both parties can check they are trading the same option.
+    pub fn specs(env: Env, series: u64) -> Result<Specs, Error> {
+        let def: OptionDef = get_series(&env, series)?;
+        let state: State = get_state(&env, series, &def);
+        Ok(Specs { def, state })
+    }
+
+    // Dump the current trade and oracle info of a series in one call
+    pub fn info(env: Env, series: u64) -> Result<Info, Error> {
+        let def: OptionDef = get_series(&env, series)?;
+        let store = env.storage().persistent();
+        Ok(Info {
+            buyer: get_party(&env, &DataKey::BAdr(series)),
+            seller: get_party(&env, &DataKey::SAdr(series)),
+            buyer_dep: store.get(&DataKey::BDep(series)).unwrap_or(0),
+            seller_dep: store.get(&DataKey::SDep(series)).unwrap_or(0),
+            balance: store.get(&DataKey::Balance(series)).unwrap_or(0),
+            mkt_price: store.get(&DataKey::MktPrice(series)).unwrap_or(0),
+            oracle_ts: store.get(&DataKey::OracleTs(series)).unwrap_or(0),
+            oracle_flags: store.get(&DataKey::OracleFlags(series)).unwrap_or(0),
+            trade_id: store.get(&DataKey::TradeId(series)).unwrap_or(0),
+            state: get_state(&env, series, &def),
+        })
+    }
+
+    // The admin of a series can change how old an oracle quote can be until
+    // the settlement price is in.
+    pub fn set_staleness(env: Env, series: u64, max_staleness: u64) -> Result<(), Error> {
+        let mut def: OptionDef = get_series(&env, series)?;
+        def.admin.require_auth();
+
+        if max_staleness == 0 {
+            return Err(Error::InvalidInput);
+        }
+        let state: State = load_state(&env, series);
+        if state == State::SettlementPriced || state == State::Settled {
+            return Err(Error::InvalidState);
+        }
+        def.max_staleness = max_staleness;
+        env.storage().persistent().set(&DataKey::Series(series), &def);
+        Ok(())
+    }
+
     // The seller deposits USDC to the contract in the amount of
-    // strike price - option premium * number of options.
-    // Example: Strike price is 100, premium is 10, number of options is 10.
+    // (maximum payout - option premium) * number of options.
+    // Example: Put strike price is 100, premium is 10, number of options is 10.
     // Seller deposits 900 USDC.  This represents the seller's obligation in
-    // worst case scenario of the asset price going to 0.
+    // worst case scenario of the asset price going to 0.  Calls pay at most
+    // their cap less their strike, see max_px().
+    #[allow(clippy::too_many_arguments)]
     pub fn trade(
         env: Env,
+        series: u64,
         counter_party: Address,
         token: Address,
         side: u32, // 0 = seller, 1 = buyer
@@ -237,41 +408,81 @@ impl OptionContract {
         decimals: u32,
         qty: i128,
         trade_id: u64,
-    ) {
+    ) -> Result<(), Error> {
         counter_party.require_auth();
 
         if !is_initialized(&env) {
-            panic!("contract not initialized");
+            return Err(Error::NotInitialized);
         }
 
         // Get the option details
-        let strike: i128 = env.storage().instance().get(&DataKey::Strike).unwrap();
-        let exp: TimeBound = env.storage().instance().get(&DataKey::Expiration).unwrap();
-        let trd_id: u64 = env.storage().instance().get(&DataKey::TradeId).unwrap();
+        let def: OptionDef = get_series(&env, series)?;
+        let max_price: i128 = max_px(&def)?;
+        let exp: TimeBound = def.exp.clone();
 
-        let mut seller_deposit: i128 = env.storage().instance().get(&DataKey::SDep).unwrap();
-        let mut buyer_deposit: i128 = env.storage().instance().get(&DataKey::BDep).unwrap();
-
-        let opt_decimals: u32 = env.storage().instance().get(&DataKey::Decimals).unwrap();
+        let opt_decimals: u32 = def.decimals;
 
         // TODO: convert if necessary
         if decimals != opt_decimals {
-            panic!("decimals mismatch");
+            return Err(Error::DecimalsMismatch);
         }
 
-        if check_time_bound(&env, &exp) {
-            panic!("past expiration date time");
+        let state: State = get_state(&env, series, &def);
+        if state == State::Expired {
+            return Err(Error::Expired);
+        }
+        if state != State::Listed && state != State::HalfFilled && state != State::Cancelled {
+            return Err(Error::InvalidState);
         }
 
-        if side == SIDE_SELL {
-            if trade_id != 0 && trade_id != trd_id {
-                panic!("trade already exists or invalid trade id");
+        if side != SIDE_SELL && side != SIDE_BUY {
+            return Err(Error::InvalidInput);
+        }
+        if token != def.collateral_token {
+            return Err(Error::InvalidInput);
+        }
+        if qty <= 0 {
+            return Err(Error::InvalidInput);
+        }
+        if price < 0 || price > max_price {
+            return Err(Error::InvalidInput);
+        }
+        // The deposits add up to the maximum payout, it has to fit
+        mul(max_price, qty)?;
+
+        // The first side of the trade sets the price, quantity and trade id,
+        // the second side has to match them exactly before the match window
+        // has passed.
+        let first_side: bool = state != State::HalfFilled;
+        if first_side {
+            // The match window can't run past the expiration
+            let match_exp: u64 = exp.timestamp.min(env.ledger().timestamp().saturating_add(def.match_window));
+            env.storage().persistent().set(&DataKey::TradePx(series), &price);
+            env.storage().persistent().set(&DataKey::TradeQty(series), &qty);
+            env.storage().persistent().set(&DataKey::TradeId(series), &trade_id);
+            env.storage().persistent().set(&DataKey::MatchExp(series), &match_exp);
+        } else {
+            let trd_id: u64 = env.storage().persistent().get(&DataKey::TradeId(series)).unwrap();
+            let trade_px: i128 = env.storage().persistent().get(&DataKey::TradePx(series)).unwrap();
+            let trade_qty: i128 = env.storage().persistent().get(&DataKey::TradeQty(series)).unwrap();
+            let match_exp: u64 = env.storage().persistent().get(&DataKey::MatchExp(series)).unwrap();
+            if env.ledger().timestamp() >= match_exp {
+                return Err(Error::InvalidState);
             }
-            if seller_deposit > 0 {
-                panic!("seller deposit already exists");
+            if trade_id != trd_id {
+                return Err(Error::InvalidInput);
+            }
+            if price != trade_px || qty != trade_qty {
+                return Err(Error::InvalidInput);
+            }
+        }
+
+        if side == SIDE_SELL {
+            if env.storage().persistent().has(&DataKey::SAdr(series)) {
+                return Err(Error::DepositExists);
             }
             // Calculate the new deposit requirements
-            seller_deposit = (strike - price) * qty;
+            let seller_deposit: i128 = mul(max_price - price, qty)?;
 
             // Transfer token from `counter_party` to this contract address.
             token::Client::new(&env, &token).transfer(
@@ -279,19 +490,16 @@ impl OptionContract {
                 &env.current_contract_address(),
                 &seller_deposit,
             );
+            add_balance(&env, series, seller_deposit);
             // Update the trade variables
-            env.storage().instance().set(&DataKey::SDep, &seller_deposit);
-            env.storage().instance().set(&DataKey::TradeId, &trade_id);
-            env.storage().instance().set(&DataKey::SAdr, &counter_party);
-        } else if side == SIDE_BUY {
-            if trade_id != 0 && trade_id != trd_id {
-                panic!("trade already exists or invalid trade id");
-            }
-            if buyer_deposit > 0 {
-                panic!("buyer deposit already exists");
+            env.storage().persistent().set(&DataKey::SDep(series), &seller_deposit);
+            env.storage().persistent().set(&DataKey::SAdr(series), &counter_party);
+        } else {
+            if env.storage().persistent().has(&DataKey::BAdr(series)) {
+                return Err(Error::DepositExists);
             }
             // Calculate the new deposit requirements
-            buyer_deposit = price * qty;
+            let buyer_deposit: i128 = mul(price, qty)?;
 
             // Transfer token from `counter_party` to this contract address.
             token::Client::new(&env, &token).transfer(
@@ -299,79 +507,296 @@ impl OptionContract {
                 &env.current_contract_address(),
                 &buyer_deposit,
             );
+            add_balance(&env, series, buyer_deposit);
             // Update the trade variables
-            env.storage().instance().set(&DataKey::BDep, &buyer_deposit);
-            env.storage().instance().set(&DataKey::TradeId, &trade_id);
-            env.storage().instance().set(&DataKey::BAdr, &counter_party);
+            env.storage().persistent().set(&DataKey::BDep(series), &buyer_deposit);
+            env.storage().persistent().set(&DataKey::BAdr(series), &counter_party);
+        }
+
+        // Both deposits are in once the second side has traded
+        if first_side {
+            set_state(&env, series, State::HalfFilled);
+        } else {
+            set_state(&env, series, State::Matched);
+
+            let buyer_adr: Address = env.storage().persistent().get(&DataKey::BAdr(series)).unwrap();
+            let seller_adr: Address = env.storage().persistent().get(&DataKey::SAdr(series)).unwrap();
+            add_position(&env, series, &def, &buyer_adr, qty, price)?;
+            add_position(&env, series, &def, &seller_adr, -qty, price)?;
+
+            // The buyer's premium and the seller's deposit together cover the
+            // maximum payout, they are held as the seller's collateral.
+            add_collateral(&env, series, &seller_adr, mul(max_price, qty)?);
+            release_excess(&env, series, &def, &seller_adr)?;
+
+            let index: u32 = log_trade(&env, series, &Trade {
+                price,
+                decimals,
+                qty,
+                buyer: buyer_adr,
+                seller: seller_adr,
+                date_time: env.ledger().timestamp(),
+                trade_id,
+            });
+            env.storage().persistent().set(&DataKey::TrdIdx(series, trade_id), &index);
+        }
+        Ok(())
+    }
+
+    // Return up to limit trades of a series, oldest first, starting at start
+    pub fn trades(env: Env, series: u64, start: u32, limit: u32) -> Result<Vec<Trade>, Error> {
+        get_series(&env, series)?;
+        let n_trds: u32 = env.storage().persistent().get(&DataKey::NTrds(series)).unwrap_or(0);
+
+        let mut trds: Vec<Trade> = Vec::new(&env);
+        for index in start..n_trds.min(start.saturating_add(limit)) {
+            trds.push_back(env.storage().persistent().get(&DataKey::Trd(series, index)).unwrap());
+        }
+        Ok(trds)
+    }
+
+    // Return the matched trade with the given trade id, transfers can't be
+    // looked up by trade id
+    pub fn get_trade(env: Env, series: u64, trade_id: u64) -> Result<Trade, Error> {
+        get_series(&env, series)?;
+        let index: u32 = match env.storage().persistent().get(&DataKey::TrdIdx(series, trade_id)) {
+            Some(index) => index,
+            None => return Err(Error::InvalidInput),
+        };
+        Ok(env.storage().persistent().get(&DataKey::Trd(series, index)).unwrap())
+    }
+
+    // Transfer qty options of an open position from one account to another at
+    // price.  Both accounts have to authorize the transfer.  For a long
+    // position the new holder pays the price to the old one.  For a short
+    // position the new holder posts the maximum payout less the price as
+    // collateral and the old holder gets the same amount back from its
+    // collateral.
+    pub fn transfer_position(env: Env, series: u64, from: Address, to: Address, qty: i128, price: i128) -> Result<(), Error> {
+        if !is_initialized(&env) {
+            return Err(Error::NotInitialized);
+        }
+
+        from.require_auth();
+        to.require_auth();
+
+        let def: OptionDef = get_series(&env, series)?;
+        let max_price: i128 = max_px(&def)?;
+        check_matched(&env, series, &def)?;
+        if from == to {
+            return Err(Error::InvalidInput);
+        }
+        if price < 0 || price > max_price {
+            return Err(Error::InvalidInput);
+        }
+
+        let from_pos: i128 = Self::position(env.clone(), series, from.clone())?.pos;
+        if qty <= 0 || qty > from_pos.abs() {
+            return Err(Error::InvalidInput);
+        }
+
+        let token = token::Client::new(&env, &def.collateral_token);
+        let max_payout: i128 = mul(max_price, qty)?;
+        let (buyer, seller) = if from_pos > 0 {
+            // The new holder buys the long position
+            token.transfer(&to, &from, &mul(price, qty)?);
+            add_position(&env, series, &def, &from, -qty, price)?;
+            add_position(&env, series, &def, &to, qty, price)?;
+            (to.clone(), from)
         } else {
-            panic!("invalid side");
+            // The new holder takes over the short position and its collateral
+            let margin: i128 = mul(max_price - price, qty)?;
+            token.transfer(&to, &env.current_contract_address(), &margin);
+            token.transfer(&env.current_contract_address(), &from, &margin);
+            add_collateral(&env, series, &to, max_payout);
+            add_collateral(&env, series, &from, -max_payout);
+            add_position(&env, series, &def, &from, qty, price)?;
+            add_position(&env, series, &def, &to, -qty, price)?;
+            (from, to.clone())
+        };
+
+        // The new holder may have had an offsetting position
+        release_excess(&env, series, &def, &to)?;
+
+        // Transfers are logged with the trades, they don't have a trade id
+        log_trade(&env, series, &Trade {
+            price,
+            decimals: def.decimals,
+            qty,
+            buyer,
+            seller,
+            date_time: env.ledger().timestamp(),
+            trade_id: 0,
+        });
+        Ok(())
+    }
+
+    // Release the collateral of an account that is not needed to cover its
+    // net short position, e.g. after it has bought and sold the same series.
+    // Returns the amount released.
+    pub fn net(env: Env, series: u64, acct: Address) -> Result<i128, Error> {
+        if !is_initialized(&env) {
+            return Err(Error::NotInitialized);
+        }
+
+        acct.require_auth();
+
+        let def: OptionDef = get_series(&env, series)?;
+        check_matched(&env, series, &def)?;
+        release_excess(&env, series, &def, &acct)
+    }
+
+    // Return the net position of an account in a series
+    pub fn position(env: Env, series: u64, acct: Address) -> Result<Position, Error> {
+        let def: OptionDef = get_series(&env, series)?;
+        match env.storage().persistent().get(&DataKey::Pos(series, acct.clone())) {
+            Some(position) => Ok(position),
+            None => Ok(Position {
+                pos: 0,
+                price: 0,
+                acct,
+                token: def.collateral_token,
+            }),
         }
     }
 
+    // Return the positions of all the accounts that have traded a series
+    pub fn positions(env: Env, series: u64) -> Result<Vec<Position>, Error> {
+        get_series(&env, series)?;
+        let n_accts: u32 = env.storage().persistent().get(&DataKey::NAccts(series)).unwrap_or(0);
+
+        let mut r: Vec<Position> = Vec::new(&env);
+        for index in 0..n_accts {
+            let acct: Address = env.storage().persistent().get(&DataKey::Acct(series, index)).unwrap();
+            r.push_back(env.storage().persistent().get(&DataKey::Pos(series, acct)).unwrap());
+        }
+        Ok(r)
+    }
+
+    // Can be called by the buyer or seller that traded first to get its
+    // deposit back if the other side has not traded by the end of the match
+    // window, or once the series has expired.  The series can then be traded
+    // again.
+    pub fn cancel_unmatched(env: Env, series: u64, party: Address) -> Result<(), Error> {
+        if !is_initialized(&env) {
+            return Err(Error::NotInitialized);
+        }
+
+        party.require_auth();
+
+        let def: OptionDef = get_series(&env, series)?;
+        let state: State = load_state(&env, series);
+        // Once matched the deposits are collateral, even after the oracle has
+        // moved the series to Expired
+        if is_matched(&env, series) {
+            return Err(Error::InvalidState);
+        }
+        // A half filled series that expired before the oracle moved it to
+        // Expired still has its pending trade.
+        if !env.storage().persistent().has(&DataKey::TradeQty(series)) {
+            return Err(Error::InvalidState);
+        }
+        let match_exp: u64 = env.storage().persistent().get(&DataKey::MatchExp(series)).unwrap();
+        if env.ledger().timestamp() < match_exp {
+            return Err(Error::InvalidState);
+        }
+
+        let refund: i128;
+        if env.storage().persistent().get(&DataKey::SAdr(series)) == Some(party.clone()) {
+            refund = env.storage().persistent().get(&DataKey::SDep(series)).unwrap();
+            env.storage().persistent().remove(&DataKey::SAdr(series));
+            env.storage().persistent().set(&DataKey::SDep(series), &0_i128);
+        } else if env.storage().persistent().get(&DataKey::BAdr(series)) == Some(party.clone()) {
+            refund = env.storage().persistent().get(&DataKey::BDep(series)).unwrap();
+            env.storage().persistent().remove(&DataKey::BAdr(series));
+            env.storage().persistent().set(&DataKey::BDep(series), &0_i128);
+        } else {
+            return Err(Error::InvalidInput);
+        }
+
+        // Clear the unmatched trade
+        env.storage().persistent().remove(&DataKey::TradePx(series));
+        env.storage().persistent().remove(&DataKey::TradeQty(series));
+        env.storage().persistent().remove(&DataKey::MatchExp(series));
+        env.storage().persistent().set(&DataKey::TradeId(series), &0_u64);
+        if check_time_bound(&env, &def.exp) {
+            if state != State::Expired {
+                set_state(&env, series, State::Expired);
+            }
+        } else if state == State::HalfFilled {
+            set_state(&env, series, State::Cancelled);
+        }
+
+        if refund > 0 {
+            add_balance(&env, series, -refund);
+            token::Client::new(&env, &def.collateral_token).transfer(
+                &env.current_contract_address(),
+                &party,
+                &refund,
+            );
+        }
+        Ok(())
+    }
+
     // The function calls the oracle to provide the price of the underlying
     // asset.  The contract checks that the price is above/below the strike
     // price and allows the buyer/seller to claim the calculated balances if
-    // the expiration is passed.
+    // the expiration is passed.  The oracle licenses its prices to this
+    // contract, so only the admin of the series or an account that has traded
+    // it can pull a price, and the price is not returned.
     // TODO: Figure out if this will be a pull or be called from the oracle.
-    pub fn upd_px(env: Env) -> Vec<i128> {
+    pub fn upd_px(env: Env, series: u64, caller: Address) -> Result<(), Error> {
         if !is_initialized(&env) {
-            panic!("contract not initialized");
+            return Err(Error::NotInitialized);
         }
-        let oracle_contract_id: Address = env.storage().instance().get(&DataKey::Oracle).unwrap();
 
-        // // Get without importing the oracle contract
-        // let mut oracle_data: Vec<i128> = env.invoke_contract(
-        //     &oracle_contract_id,
-        //     &symbol_short!("retrieve"),
-        //     Vec::new(&env)
-        // );
+        caller.require_auth();
 
-        // Get with importing the oracle contract wasm
-        let client = oracle::Client::new(&env, &oracle_contract_id);
-        let oracle_data: Vec<i128> = client.retrieve();
-
-        // Store the data
+        let def: OptionDef = get_series(&env, series)?;
+        check_party(&env, series, &def, &caller)?;
+        pull_px(&env, series, &def, &caller)?;
+        Ok(())
+    }
 
-        env.storage().instance().set(
-            &DataKey::OracleSymbol,
-            &oracle_data.get(0).unwrap(),
-        );
-        env.storage().instance()
-            .set(&DataKey::MktPrice, &oracle_data.get(1).unwrap());
-        env.storage().instance()
-            .set(&DataKey::OracleTs, &oracle_data.get(2).unwrap());
-        env.storage().instance()
-            .set(&DataKey::OracleFlags, &oracle_data.get(3).unwrap());
-        oracle_data
-    }
-
-    // Get the current buyer obligation, seller obligation, and market price.
-    // Example: Strike price is 100, trade price was 10, number of options is 10.
-    // Current market price is 50.  Buyer is entitled to 500 USDC.  Seller is
-    // entitled to 500 USDC.
-    pub fn mtm(env: Env) -> Vec<i128> {
+    // Get the caller's buyer obligation, seller obligation, and the payouts
+    // of its position at the current market price.  A long position only has
+    // the buyer's side, a short one the seller's.
+    // Example: Put strike price is 100, the caller bought 10 options at 10.
+    // The buyer's obligation is the 100 USDC premium.  Current market price is
+    // 50, the buyer is entitled to 500 USDC.  Had the caller sold them, its
+    // obligation would be 900 USDC and it would be entitled to 500 USDC.
+    pub fn mtm(env: Env, series: u64, caller: Address) -> Result<Vec<i128>, Error> {
         if !is_initialized(&env) {
-            panic!("contract not initialized");
+            return Err(Error::NotInitialized);
+        }
+
+        caller.require_auth();
+
+        let def: OptionDef = get_series(&env, series)?;
+        check_party(&env, series, &def, &caller)?;
+        if load_state(&env, series) != State::Matched {
+            return Err(Error::InvalidState);
         }
         // Update the market price from the oracle.
-        let oracle_data: Vec<i128> = Self::upd_px(env.clone());
+        let quote: oracle::UpdData = pull_px(&env, series, &def, &caller)?;
 
-        // Get the option details and the trade details
-        let strike: i128 = env.storage().instance().get(&DataKey::Strike).unwrap();
-        let trade_price: i128 = env.storage().instance().get(&DataKey::TradePx).unwrap();
-        let trade_qty: i128 = env.storage().instance().get(&DataKey::TradeQty).unwrap();
-        let market_price: i128 = oracle_data.get(1).unwrap();
+        // Get the option details and the caller's position
+        let max_price: i128 = max_px(&def)?;
+        let position: Position = Self::position(env.clone(), series, caller)?;
+        let long_qty: i128 = position.pos.max(0);
+        let short_qty: i128 = (-position.pos).max(0);
+        let market_price: i128 = quote.price;
 
         // These are the original obligations of the buyer/seller.
-        let buyer_obligation: i128 = trade_qty * (strike - trade_price);
-        let seller_obligation: i128 = trade_qty * (trade_price - strike);
+        let buyer_obligation: i128 = mul(long_qty, position.price)?;
+        let seller_obligation: i128 = mul(short_qty, max_price - position.price)?;
 
         // These would be the payouts if the buyer/seller exercised the option.
-        // or if the expiration is passed.  This is a simple european put option.
-        let buyer_payout: i128 = trade_qty * (market_price - strike);
-        let seller_payout: i128 = trade_qty * (strike - market_price);
+        // or if the expiration is passed.
+        let option_price: i128 = opt_px(&def, market_price)?;
+        let buyer_payout: i128 = mul(long_qty, option_price)?;
+        let seller_payout: i128 = mul(short_qty, max_price - option_price)?;
 
-        // TODO: Return a vector of i128 with the data above
         let mut r: Vec<i128> = Vec::new(&env);
         r.push_back(buyer_obligation);
         r.push_back(seller_obligation);
@@ -379,71 +804,143 @@ impl OptionContract {
         r.push_back(seller_payout);
 
         // Return r
-        r
+        Ok(r)
     }
 
-    // Can be called by the buyer or seller to claim the results of the trade
-    // if the expiration is passed.
-    pub fn settle(env: Env, counter_party: Address) {
-        let exp: TimeBound = env.storage().instance().get(&DataKey::Expiration).unwrap();
+    // Can be called by a holder of a long position in an American option to
+    // exercise qty options before the expiration.  The holder is paid the
+    // value of the options at a fresh regular session price from the oracle.
+    // The same quantity of short positions is assigned in the order the
+    // accounts first traded the series, each assigned seller gets back the
+    // rest of its collateral for the assigned options.
+    pub fn exercise(env: Env, series: u64, holder: Address, qty: i128) -> Result<(), Error> {
+        if !is_initialized(&env) {
+            return Err(Error::NotInitialized);
+        }
+
+        holder.require_auth();
+
+        let def: OptionDef = get_series(&env, series)?;
+        if def.opt_type & AMERICAN == 0 {
+            return Err(Error::InvalidInput);
+        }
+        check_matched(&env, series, &def)?;
+        let holder_pos: i128 = Self::position(env.clone(), series, holder.clone())?.pos;
+        if qty <= 0 || qty > holder_pos {
+            return Err(Error::InvalidInput);
+        }
+
+        // Exercise against a fresh price taken during the regular session.
+        let quote: oracle::UpdData = pull_px(&env, series, &def, &holder)?;
+        let oracle_flags: i128 = quote.flags;
+        if oracle_flags & ORACLE_REGULAR == 0 {
+            return Err(Error::OracleNotFinal);
+        }
+
+        let option_price: i128 = opt_px(&def, quote.price)?;
+        let max_price: i128 = max_px(&def)?;
+        let token = token::Client::new(&env, &def.collateral_token);
+        let payout: i128 = mul(qty, option_price)?;
+
+        add_position(&env, series, &def, &holder, -qty, option_price)?;
+        if payout > 0 {
+            add_balance(&env, series, -payout);
+            token.transfer(&env.current_contract_address(), &holder, &payout);
+        }
+
+        // Assign the exercised options to the sellers
+        let n_accts: u32 = env.storage().persistent().get(&DataKey::NAccts(series)).unwrap();
+        let mut remaining: i128 = qty;
+        for index in 0..n_accts {
+            if remaining == 0 {
+                break;
+            }
+            let acct: Address = env.storage().persistent().get(&DataKey::Acct(series, index)).unwrap();
+            let position: Position = env.storage().persistent().get(&DataKey::Pos(series, acct.clone())).unwrap();
+            if position.pos >= 0 {
+                continue;
+            }
+            let assigned: i128 = remaining.min(-position.pos);
+            remaining -= assigned;
+
+            let refund: i128 = mul(assigned, max_price - option_price)?;
+
+            add_position(&env, series, &def, &acct, assigned, option_price)?;
+            add_collateral(&env, series, &acct, -mul(assigned, max_price)?);
+            if refund > 0 {
+                add_balance(&env, series, -refund);
+                token.transfer(&env.current_contract_address(), &acct, &refund);
+            }
+        }
+        Ok(())
+    }
+
+    // Can be called by an account holding a position or collateral in the
+    // series to claim the results of its trades if the expiration is passed
+    // and upd_px() has picked up a settlement price taken within
+    // max_staleness of the expiration.
+    pub fn settle(env: Env, series: u64, counter_party: Address) -> Result<(), Error> {
+        let def: OptionDef = get_series(&env, series)?;
+        let exp: TimeBound = def.exp.clone();
 
         if !check_time_bound(&env, &exp) {
-            panic!("time predicate is not fulfilled");
+            return Err(Error::InvalidState);
         }
 
         // Only the buyer or the seller can call this function.
         counter_party.require_auth();
 
-        let strike: i128 = env.storage().instance().get(&DataKey::Strike).unwrap();
-        //let trade_price: i128 = env.storage().instance().get(&DataKey::TradePx).unwrap();
-        let trade_qty: i128 = env.storage().instance().get(&DataKey::TradeQty).unwrap();
-        let market_price: i128 = env.storage().instance().get(&DataKey::MktPrice).unwrap();
-        let token: Address = env.storage().instance().get(&DataKey::Token).unwrap();
-        let buyer_adr: Address = env.storage().instance().get(&DataKey::BAdr).unwrap();
-        let seller_adr: Address = env.storage().instance().get(&DataKey::SAdr).unwrap();
-        let trade_px: i128 = env.storage().instance().get(&DataKey::TradePx).unwrap();
-        let buyer_deposit: i128 = env.storage().instance().get(&DataKey::BDep).unwrap();
-        let seller_deposit: i128 = env.storage().instance().get(&DataKey::SDep).unwrap();
+        // A series that never matched has nothing to settle, a matched one
+        // waits for the settlement price
+        if !is_matched(&env, series) {
+            return Err(Error::InvalidState);
+        }
+        match load_state(&env, series) {
+            State::SettlementPriced => {}
+            State::Settled => return Err(Error::InvalidState),
+            _ => return Err(Error::OracleNotFinal),
+        }
+
+        let market_price: i128 = env.storage().persistent().get(&DataKey::MktPrice(series)).unwrap();
+        let token: Address = def.collateral_token.clone();
 
-        // Only the buyer or the seller can call this function.
-        if counter_party != buyer_adr && counter_party != seller_adr {
-            panic!("invalid counter party");
+        // Only an account that has traded the series can call this function.
+        let position: Position = match env.storage().persistent().get(&DataKey::Pos(series, counter_party.clone())) {
+            Some(position) => position,
+            None => return Err(Error::InvalidInput),
+        };
+        // and only once
+        let claimed_key = DataKey::Claimed(series, counter_party.clone());
+        if env.storage().persistent().has(&claimed_key) {
+            return Err(Error::InvalidState);
         }
 
-        // Check the market price flags to see if the oracle is valid and we
-        // have a settlement price.
-        let oracle_flags: i128 = env.storage().instance().get(&DataKey::OracleFlags).unwrap();
-        if oracle_flags != 1 {
-            panic!("oracle flags not valid, no settlement price update");
+        // A seller's collateral covers the maximum payout of its short
+        // position, a buyer is paid the option value at the settlement price
+        // out of it.  Any collateral left over belongs to the account.
+        let option_price: i128 = opt_px(&def, market_price)?;
+        let payout: i128 = get_collateral(&env, series, &counter_party) + mul(position.pos, option_price)?;
+
+        if payout < 0 {
+            return Err(Error::NegativePayout);
+        }
+        let balance: i128 = env.storage().persistent().get(&DataKey::Balance(series)).unwrap();
+        if payout > balance {
+            return Err(Error::NegativePayout);
         }
 
-        // These would be the payouts if the buyer/seller exercised the option.
-        let put_price: i128 = put_px(strike, market_price);
-        // or if the expiration is passed.  This is a simple european put option.
-        let buyer_settle: i128 = trade_qty * (put_price - trade_px); // If put_price = 0, buyer_settle < 0
-        let seller_settle: i128 = trade_qty * (trade_px - put_price); // If put_price = 0, seller_settle > 0
-
-        let buyer_payout: i128 = buyer_deposit - buyer_settle;
-        let seller_payout: i128 = seller_deposit + seller_settle;
-
-        let mut payout: i128 = 0;
-        if counter_party == buyer_adr {
-            if buyer_payout < 0 {
-                panic!("buyer payout is negative");
-            }
-            if buyer_payout > 0 {
-                payout = buyer_payout;
-            }
+        // Record the claim before paying out
+        env.storage().persistent().set(&claimed_key, &true);
+        env.storage().persistent().set(&DataKey::Coll(series, counter_party.clone()), &0_i128);
+        if env.storage().persistent().get(&DataKey::BAdr(series)) == Some(counter_party.clone()) {
+            env.storage().persistent().set(&DataKey::BDep(series), &0_i128);
         }
-        if counter_party == seller_adr {
-            if seller_payout < 0 {
-                panic!("seller payout is negative");
-            }
-            if seller_payout > 0 {
-                payout = seller_payout;
-            }
+        if env.storage().persistent().get(&DataKey::SAdr(series)) == Some(counter_party.clone()) {
+            env.storage().persistent().set(&DataKey::SDep(series), &0_i128);
         }
+
         if payout > 0 {
+            add_balance(&env, series, -payout);
             // Transfer the stored amount of token to claimant after passing
             // all the checks.
             token::Client::new(&env, &token).transfer(
@@ -452,6 +949,12 @@ impl OptionContract {
                 &payout,
             );
         }
+
+        // The series is done once all of its collateral has been paid out
+        if balance == payout {
+            set_state(&env, series, State::Settled);
+        }
+        Ok(())
     }
 }
 
@@ -459,141 +962,484 @@ fn is_initialized(env: &Env) -> bool {
     env.storage().instance().has(&DataKey::Init)
 }
 
+fn load_state(env: &Env, series: u64) -> State {
+    env.storage().persistent().get(&DataKey::State(series)).unwrap()
+}
+
+fn set_state(env: &Env, series: u64, state: State) {
+    env.storage().persistent().set(&DataKey::State(series), &state);
+    let topic = (Symbol::new(env, "state"), series);
+    env.events().publish(topic, state);
+}
+
+// Pull the quote of the series' underlying from the oracle, store it and move
+// the series on once it is past the expiration.  The caller pays the oracle's
+// fee for the quote.
+fn pull_px(env: &Env, series: u64, def: &OptionDef, caller: &Address) -> Result<oracle::UpdData, Error> {
+    let state: State = load_state(env, series);
+    if state == State::SettlementPriced || state == State::Settled {
+        return Err(Error::InvalidState);
+    }
+    let oracle_contract_id: Address = def.oracle.clone();
+
+    // // Get without importing the oracle contract
+    // let quote: oracle::UpdData = env.invoke_contract(
+    //     &oracle_contract_id,
+    //     &symbol_short!("retrieve"),
+    //     vec![env, env.current_contract_address().to_val(), def.symbol.to_val()]
+    // );
+
+    // Get the quote of the series' underlying with importing the oracle
+    // contract wasm, this contract has to be a user of the oracle
+    let client = oracle::Client::new(env, &oracle_contract_id);
+
+    // The retrieve takes the fee from this contract's credit with the oracle,
+    // credit prepaid for the contract is spent first and the caller only
+    // tops it up to the fee
+    let fee: i128 = match client.try_fee() {
+        Ok(Ok(fee)) => fee,
+        _ => return Err(Error::OracleNotFinal),
+    };
+    let credit: i128 = match client.try_credit_of(&env.current_contract_address()) {
+        Ok(Ok(credit)) => credit,
+        _ => return Err(Error::OracleNotFinal),
+    };
+    if fee > credit {
+        match client.try_deposit_credit(caller, &env.current_contract_address(), &(fee - credit)) {
+            Ok(Ok(_)) => {}
+            _ => return Err(Error::OracleFee),
+        }
+    }
+    let quote: oracle::UpdData = match client.try_retrieve(&env.current_contract_address(), &def.symbol) {
+        Ok(Ok(quote)) => quote,
+        Err(Ok(e)) if e == oracle::Error::LowCredit.into() => return Err(Error::OracleFee),
+        _ => return Err(Error::OracleNotFinal),
+    };
+    if !is_fresh(env, def, &quote) {
+        return Err(Error::OracleNotFinal);
+    }
+
+    // Store the data
+    env.storage().persistent().set(&DataKey::OracleSymbol(series), &quote.instrument);
+    env.storage().persistent().set(&DataKey::MktPrice(series), &quote.price);
+    env.storage().persistent().set(&DataKey::OracleTs(series), &quote.timestamp);
+    env.storage().persistent().set(&DataKey::OracleFlags(series), &quote.flags);
+
+    // Past the expiration a matched series waits for the settlement price,
+    // a series that never matched can only be cancelled.  It always passes
+    // through Expired so that transition is published too.
+    if check_time_bound(env, &def.exp) {
+        if state != State::Expired {
+            set_state(env, series, State::Expired);
+        }
+        if is_matched(env, series) && quote.flags & ORACLE_SETTLE != 0 {
+            set_state(env, series, State::SettlementPriced);
+        }
+    }
+    Ok(quote)
+}
+
+// Only the admin of a series and the accounts that have traded it
+fn check_party(env: &Env, series: u64, def: &OptionDef, acct: &Address) -> Result<(), Error> {
+    let store = env.storage().persistent();
+    if *acct == def.admin
+        || store.has(&DataKey::Pos(series, acct.clone()))
+        || store.get::<_, Address>(&DataKey::BAdr(series)).as_ref() == Some(acct)
+        || store.get::<_, Address>(&DataKey::SAdr(series)).as_ref() == Some(acct)
+    {
+        return Ok(());
+    }
+    Err(Error::InvalidInput)
+}
+
+// Whether the series has ever matched, only a match gives accounts positions
+fn is_matched(env: &Env, series: u64) -> bool {
+    env.storage().persistent().has(&DataKey::NAccts(series))
+}
+
+// The stored state, a series that is past the expiration is reported as
+// Expired until the oracle moves it on.
+fn get_state(env: &Env, series: u64, def: &OptionDef) -> State {
+    let state: State = load_state(env, series);
+    match state {
+        State::Listed | State::HalfFilled | State::Matched | State::Cancelled
+            if check_time_bound(env, &def.exp) =>
+        {
+            State::Expired
+        }
+        _ => state,
+    }
+}
+
+// Positions can only change while the series is matched and not expired
+fn check_matched(env: &Env, series: u64, def: &OptionDef) -> Result<(), Error> {
+    match get_state(env, series, def) {
+        State::Matched => Ok(()),
+        State::Listed | State::HalfFilled | State::Cancelled => Err(Error::InvalidState),
+        _ => Err(Error::Expired),
+    }
+}
+
+// Append a trade to the trade history of a series, each trade has its own
+// entry so the history can grow.  Returns the index of the trade.
+fn log_trade(env: &Env, series: u64, trd: &Trade) -> u32 {
+    let index: u32 = env.storage().persistent().get(&DataKey::NTrds(series)).unwrap_or(0);
+    env.storage().persistent().set(&DataKey::Trd(series, index), trd);
+    env.storage().persistent().set(&DataKey::NTrds(series), &(index + 1));
+    index
+}
+
+// Add qty traded at price to the net position of acct, long > 0, short < 0.
+// Adding to the position moves its average price, reducing it keeps it.
+fn add_position(env: &Env, series: u64, def: &OptionDef, acct: &Address, qty: i128, price: i128) -> Result<(), Error> {
+    let key = DataKey::Pos(series, acct.clone());
+    let mut position: Position = match env.storage().persistent().get(&key) {
+        Some(position) => position,
+        None => {
+            let n_accts: u32 = env.storage().persistent().get(&DataKey::NAccts(series)).unwrap_or(0);
+            env.storage().persistent().set(&DataKey::Acct(series, n_accts), acct);
+            env.storage().persistent().set(&DataKey::NAccts(series), &(n_accts + 1));
+            Position {
+                pos: 0,
+                price: 0,
+                acct: acct.clone(),
+                token: def.collateral_token.clone(),
+            }
+        }
+    };
+    let pos: i128 = position.pos + qty;
+    if pos == 0 {
+        position.price = 0;
+    } else if position.pos == 0 || (pos > 0) != (position.pos > 0) {
+        position.price = price;
+    } else if pos.abs() > position.pos.abs() {
+        let cost: i128 = mul(position.pos.abs(), position.price)?
+            .checked_add(mul(qty.abs(), price)?)
+            .ok_or(Error::InvalidInput)?;
+        position.price = cost / pos.abs();
+    }
+    position.pos = pos;
+    env.storage().persistent().set(&key, &position);
+    Ok(())
+}
+
+// Quantity times a price, a quantity large enough to overflow is rejected
+fn mul(qty: i128, price: i128) -> Result<i128, Error> {
+    qty.checked_mul(price).ok_or(Error::InvalidInput)
+}
+
+fn get_collateral(env: &Env, series: u64, acct: &Address) -> i128 {
+    env.storage()
+        .persistent()
+        .get(&DataKey::Coll(series, acct.clone()))
+        .unwrap_or(0)
+}
+
+fn add_collateral(env: &Env, series: u64, acct: &Address, amount: i128) {
+    let collateral: i128 = get_collateral(env, series, acct) + amount;
+    env.storage().persistent().set(&DataKey::Coll(series, acct.clone()), &collateral);
+}
+
+fn get_party(env: &Env, key: &DataKey) -> Party {
+    match env.storage().persistent().get(key) {
+        Some(acct) => Party::Acct(acct),
+        None => Party::None,
+    }
+}
+
+// Track the collateral token the contract holds for a series
+fn add_balance(env: &Env, series: u64, amount: i128) {
+    let balance: i128 = env.storage().persistent().get(&DataKey::Balance(series)).unwrap_or(0);
+    env.storage().persistent().set(&DataKey::Balance(series), &(balance + amount));
+}
+
+// Pay out the collateral of acct above what its net short position needs
+fn release_excess(env: &Env, series: u64, def: &OptionDef, acct: &Address) -> Result<i128, Error> {
+    let pos: i128 = match env.storage().persistent().get::<_, Position>(&DataKey::Pos(series, acct.clone())) {
+        Some(position) => position.pos,
+        None => 0,
+    };
+    let required: i128 = if pos < 0 { mul(-pos, max_px(def)?)? } else { 0 };
+    let excess: i128 = get_collateral(env, series, acct) - required;
+    if excess > 0 {
+        add_collateral(env, series, acct, -excess);
+        add_balance(env, series, -excess);
+        token::Client::new(env, &def.collateral_token).transfer(
+            &env.current_contract_address(),
+            acct,
+            &excess,
+        );
+        return Ok(excess);
+    }
+    Ok(0)
+}
+
+fn get_series(env: &Env, series: u64) -> Result<OptionDef, Error> {
+    match env.storage().persistent().get(&DataKey::Series(series)) {
+        Some(def) => Ok(def),
+        None => Err(Error::InvalidInput),
+    }
+}
+
+// Number of strike prices an option type is listed with
+fn strike_count(opt_type: u32) -> u32 {
+    if opt_type & CONDOR != 0 {
+        return 4;
+    }
+    if opt_type & BUTTERFLY != 0 {
+        return 3;
+    }
+    if opt_type & (CALL_SPRD | PUT_SPRD) != 0 {
+        return 2;
+    }
+    // A call is listed with the cap of its payout
+    if opt_type & (BINARY | CALL) == CALL {
+        return 2;
+    }
+    1
+}
+
+// A settlement quote has to be taken at or after the expiration and no more
+// than max_staleness later.  Any other quote can be at most max_staleness
+// older than the ledger time.  Quote timestamps are in ledger time, Unix
+// seconds, and can't be ahead of the ledger.
+fn is_fresh(env: &Env, def: &OptionDef, quote: &oracle::UpdData) -> bool {
+    let max_staleness: i128 = def.max_staleness as i128;
+    if quote.timestamp > env.ledger().timestamp() as i128 {
+        return false;
+    }
+    if check_time_bound(env, &def.exp) && quote.flags & ORACLE_SETTLE != 0 {
+        let exp: i128 = def.exp.timestamp as i128;
+        return quote.timestamp >= exp && quote.timestamp - exp <= max_staleness;
+    }
+    env.ledger().timestamp() as i128 - quote.timestamp <= max_staleness
+}
+
+// Value of one option at the market price px
+fn opt_px(def: &OptionDef, px: i128) -> Result<i128, Error> {
+    let strk1_px: i128 = def.strikes.get(0).unwrap();
+    if def.opt_type & BINARY != 0 {
+        if def.opt_type & PUT != 0 {
+            return binary_put_px(strk1_px, def.payout, px);
+        }
+        return binary_call_px(strk1_px, def.payout, px);
+    }
+    if def.opt_type & CONDOR != 0 {
+        return condor_px(
+            strk1_px,
+            def.strikes.get(1).unwrap(),
+            def.strikes.get(2).unwrap(),
+            def.strikes.get(3).unwrap(),
+            px,
+        );
+    }
+    if def.opt_type & BUTTERFLY != 0 {
+        return butterfly_px(strk1_px, def.strikes.get(1).unwrap(), def.strikes.get(2).unwrap(), px);
+    }
+    if def.opt_type & CALL_SPRD != 0 {
+        return call_sprd_px(strk1_px, def.strikes.get(1).unwrap(), px);
+    }
+    if def.opt_type & PUT_SPRD != 0 {
+        return put_sprd_px(strk1_px, def.strikes.get(1).unwrap(), px);
+    }
+    if def.opt_type & PUT != 0 {
+        return put_px(strk1_px, px);
+    }
+    if def.opt_type & CALL != 0 {
+        // A call pays at most its cap less its strike, see max_px()
+        return Ok(call_px(strk1_px, px)?.min(max_px(def)?));
+    }
+    Err(Error::InvalidInput)
+}
+
+// Maximum value of one option, this is what the buyer and seller deposits add
+// up to.  A put is worth at most its strike price.  A call is listed with a cap
+// so the seller's risk is limited, it is worth at most the cap less the strike.
+// Spreads are worth at most the distance between the strikes, butterflies and
+// condors at most the wider of their wings.  Binaries pay the fixed payout or
+// nothing.
+fn max_px(def: &OptionDef) -> Result<i128, Error> {
+    if def.opt_type & BINARY != 0 {
+        return Ok(def.payout);
+    }
+    let strk1_px: i128 = def.strikes.get(0).unwrap();
+    if def.opt_type & CONDOR != 0 {
+        let low_wing: i128 = def.strikes.get(1).unwrap() - strk1_px;
+        let high_wing: i128 = def.strikes.get(3).unwrap() - def.strikes.get(2).unwrap();
+        return Ok(low_wing.max(high_wing));
+    }
+    if def.opt_type & BUTTERFLY != 0 {
+        let strk2_px: i128 = def.strikes.get(1).unwrap();
+        let low_wing: i128 = strk2_px - strk1_px;
+        let high_wing: i128 = def.strikes.get(2).unwrap() - strk2_px;
+        return Ok(low_wing.max(high_wing));
+    }
+    if def.opt_type & (CALL_SPRD | PUT_SPRD | CALL) != 0 {
+        return Ok(def.strikes.get(1).unwrap() - strk1_px);
+    }
+    if def.opt_type & PUT != 0 {
+        return Ok(strk1_px);
+    }
+    Err(Error::InvalidInput)
+}
+
 // Limited gain / loss option
-fn put_px(strk_px: i128, px: i128) -> i128 {
+fn put_px(strk_px: i128, px: i128) -> Result<i128, Error> {
     if px < 0 {
-        panic!("Price can't be < 0");
+        return Err(Error::InvalidInput);
     }
     if px >= strk_px {
-        return 0;
+        return Ok(0);
     }
 
-    return strk_px - px;
+    Ok(strk_px - px)
 }
 
 // Unlimited gain / loss option
-fn call_px(strk_px: i128, px: i128) -> i128 {
+fn call_px(strk_px: i128, px: i128) -> Result<i128, Error> {
     if px < 0 {
-        panic!("Price can't be < 0");
+        return Err(Error::InvalidInput);
     }
     if px <= strk_px {
-        return 0;
+        return Ok(0);
+    }
+    Ok(px - strk_px)
+}
+
+// Cash or nothing, pays the fixed amount if the price finishes above the strike
+fn binary_call_px(strk_px: i128, payout: i128, px: i128) -> Result<i128, Error> {
+    if px < 0 {
+        return Err(Error::InvalidInput);
+    }
+    if px > strk_px {
+        return Ok(payout);
+    }
+    Ok(0)
+}
+
+// Cash or nothing, pays the fixed amount if the price finishes below the strike
+fn binary_put_px(strk_px: i128, payout: i128, px: i128) -> Result<i128, Error> {
+    if px < 0 {
+        return Err(Error::InvalidInput);
+    }
+    if px < strk_px {
+        return Ok(payout);
     }
-    return px - strk_px;
+    Ok(0)
 }
 
 // Limited gain / loss spread
-fn call_sprd_px(strk1_px: i128, strk2_px: i128, px: i128) -> i128 {
+fn call_sprd_px(strk1_px: i128, strk2_px: i128, px: i128) -> Result<i128, Error> {
     if px < 0 {
-        panic!("Price can't be < 0");
+        return Err(Error::InvalidInput);
     }
     if strk1_px > strk2_px {
-        panic!("strk1_px > strk2_px");
+        return Err(Error::InvalidInput);
     }
     if px <= strk1_px {
-        return 0;
+        return Ok(0);
     }
     if px >= strk2_px {
-        return strk2_px - strk1_px;
+        return Ok(strk2_px - strk1_px);
     }
-    return px - strk1_px;
+    Ok(px - strk1_px)
 }
 
 // Limited gain / loss spread
-fn put_sprd_px(strk1_px: i128, strk2_px: i128, px: i128) -> i128 {
+fn put_sprd_px(strk1_px: i128, strk2_px: i128, px: i128) -> Result<i128, Error> {
     if px < 0 {
-        panic!("Price can't be < 0");
+        return Err(Error::InvalidInput);
     }
     if strk1_px > strk2_px {
-        panic!("strk1_px > strk2_px");
+        return Err(Error::InvalidInput);
     }
     if px >= strk2_px {
-        return 0;
+        return Ok(0);
     }
     if px <= strk1_px {
-        return strk2_px - strk1_px;
+        return Ok(strk2_px - strk1_px);
     }
-    return strk2_px - px;
+    Ok(strk2_px - px)
 }
 
 // Limited gain / loss spread
-fn butterfly_px(strk1_px: i128, strk2_px: i128, strk3_px: i128, px: i128) -> i128 {
+fn butterfly_px(strk1_px: i128, strk2_px: i128, strk3_px: i128, px: i128) -> Result<i128, Error> {
     if px < 0 {
-        panic!("Price can't be < 0");
+        return Err(Error::InvalidInput);
     }
     if strk1_px > strk2_px {
-        panic!("strk1_px > strk2_px");
+        return Err(Error::InvalidInput);
     }
     if strk2_px > strk3_px {
-        panic!("strk2_px > strk3_px");
+        return Err(Error::InvalidInput);
     }
     if px <= strk1_px {
-        return 0;
+        return Ok(0);
     }
     if px >= strk3_px {
-        return 0;
+        return Ok(0);
     }
     if px <= strk2_px {
-        return px - strk1_px;
+        return Ok(px - strk1_px);
     }
-    return strk3_px - px;
+    Ok(strk3_px - px)
 }
 
 // Limited gain / loss spread
-fn condor_px(strk1_px: i128, strk2_px: i128, strk3_px: i128, strk4_px: i128, px: i128) -> i128 {
+fn condor_px(strk1_px: i128, strk2_px: i128, strk3_px: i128, strk4_px: i128, px: i128) -> Result<i128, Error> {
     if px < 0 {
-        panic!("Price can't be < 0");
+        return Err(Error::InvalidInput);
     }
     if strk1_px > strk2_px {
-        panic!("strk1_px > strk2_px");
+        return Err(Error::InvalidInput);
     }
     if strk2_px > strk3_px {
-        panic!("strk2_px > strk3_px");
+        return Err(Error::InvalidInput);
     }
     if strk3_px > strk4_px {
-        panic!("strk3_px > strk4_px");
+        return Err(Error::InvalidInput);
     }
     if px <= strk1_px {
-        return strk2_px - strk1_px;
+        return Ok(strk2_px - strk1_px);
     }
     if px >= strk4_px {
-        return strk4_px - strk3_px;
+        return Ok(strk4_px - strk3_px);
     }
     if px <= strk2_px {
-        return strk2_px - px;
+        return Ok(strk2_px - px);
     }
     if px >= strk3_px {
-        return px - strk3_px;
+        return Ok(px - strk3_px);
     }
-    return 0;
+    Ok(0)
 }
 
 // Unlimited gain for long, unlimited loss for short
-fn strangle_px(strk1_px: i128, strk2_px: i128, px: i128) -> i128 {
+fn strangle_px(strk1_px: i128, strk2_px: i128, px: i128) -> Result<i128, Error> {
     if px < 0 {
-        panic!("Price can't be < 0");
+        return Err(Error::InvalidInput);
     }
     if strk1_px > strk2_px {
-        panic!("strk1_px > strk2_px");
+        return Err(Error::InvalidInput);
     }
     if px < strk1_px {
-        return strk1_px - px;
+        return Ok(strk1_px - px);
     }
     if px > strk2_px {
-        return px - strk2_px;
+        return Ok(px - strk2_px);
     }
-    return 0;
+    Ok(0)
 }
 
 // We don't support this yet
 // Unlimited gain for long, unlimited loss for short
-fn straddle_px(strk1_px: i128, px: i128) -> i128 {
+fn straddle_px(strk1_px: i128, px: i128) -> Result<i128, Error> {
     if px < 0 {
-        panic!("Price can't be < 0");
+        return Err(Error::InvalidInput);
     }
 
     let diff = px - strk1_px;
-    return diff.abs();
+    Ok(diff.abs())
 }
 
 mod test;
\ No newline at end of file
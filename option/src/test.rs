@@ -2,27 +2,732 @@
 
 extern crate std;
 
-use super::{OptionContract, OptionContractClient};
+use super::{
+    oracle, Error, OptionContract, Party, State, OptionContractClient, AMERICAN, BINARY, BUTTERFLY, CALL, CALL_SPRD, CONDOR, EUROPEAN, PUT, PUT_SPRD,
+    SIDE_BUY, SIDE_SELL,
+};
 
-use soroban_sdk::{testutils::Address as _, testutils::BytesN as _, Address, BytesN, Env};
+use soroban_sdk::{
+    testutils::{Address as _, Events, Ledger},
+    symbol_short, token, vec, Address, Env, Symbol, TryFromVal, Vec,
+};
 
-#[cfg(test)]
-
-extern crate std;
+// An initialized contract, its admin, a buyer and a seller funded with
+// 10_000 of the collateral token, and an oracle address for the listings
+struct Setup<'a> {
+    env: Env,
+    contract_id: Address,
+    client: OptionContractClient<'a>,
+    admin: Address,
+    buyer: Address,
+    seller: Address,
+    oracle_id: Address,
+    token_id: Address,
+    token: token::Client<'a>,
+    token_admin: token::AdminClient<'a>,
+}
 
-use super::{OracleContract, OracleContractClient};
+// The states published for a series, oldest first
+fn states(t: &Setup, series: u64) -> std::vec::Vec<State> {
+    let mut states = std::vec::Vec::new();
+    for (id, topics, data) in t.env.events().all().iter() {
+        if id == t.contract_id
+            && topics.len() == 2
+            && Symbol::try_from_val(&t.env, &topics.get_unchecked(0)) == Ok(Symbol::new(&t.env, "state"))
+            && u64::try_from_val(&t.env, &topics.get_unchecked(1)) == Ok(series)
+        {
+            states.push(State::try_from_val(&t.env, &data).unwrap());
+        }
+    }
+    states
+}
 
-#[test]
-fn test() {
+fn setup<'a>() -> Setup<'a> {
     let env = Env::default();
     env.mock_all_auths();
 
     let contract_id = env.register_contract(None, OptionContract);
     let client = OptionContractClient::new(&env, &contract_id);
-
-    // Test init
     client.init();
 
-    // Get the option specs
-    client.specs();
+    let admin = Address::random(&env);
+    let token_id = env.register_stellar_asset_contract(admin.clone());
+    let t = Setup {
+        contract_id,
+        client,
+        buyer: Address::random(&env),
+        seller: Address::random(&env),
+        oracle_id: Address::random(&env),
+        token: token::Client::new(&env, &token_id),
+        token_admin: token::AdminClient::new(&env, &token_id),
+        token_id,
+        admin,
+        env,
+    };
+    t.token_admin.mint(&t.buyer, &10_000);
+    t.token_admin.mint(&t.seller, &10_000);
+    t
+}
+
+impl<'a> Setup<'a> {
+    // Another account funded with 10_000 of the collateral token
+    fn account(&self) -> Address {
+        let acct = Address::random(&self.env);
+        self.token_admin.mint(&acct, &10_000);
+        acct
+    }
+
+    // Register the oracle wasm for the series listed from here on, with the
+    // contract as one of its users.  The oracle takes more than the default
+    // test budget, the test has to lift it first.
+    fn create_oracle(&mut self) -> oracle::Client<'a> {
+        self.oracle_id = self.env.register_contract_wasm(None, oracle::WASM);
+        let oracle = oracle::Client::new(&self.env, &self.oracle_id);
+        oracle.init(&Address::random(&self.env));
+        oracle.set_pxpump_user(&Address::random(&self.env));
+        oracle.add_user(&self.contract_id);
+        oracle
+    }
+
+    // List a series on SPY expiring at 1000, with 2 decimals and a match
+    // window of 100
+    fn list(&self, opt_type: u32, strikes: Vec<i128>) -> u64 {
+        self.client.list(&opt_type, &strikes, &2, &1000, &self.oracle_id, &self.token_id, &self.admin, &0, &100, &symbol_short!("SPY"))
+    }
+}
+
+#[test]
+fn test() {
+    let t = setup();
+    let series = t.list(PUT | EUROPEAN, vec![&t.env, 100]);
+
+    let specs = t.client.specs(&series);
+    assert_eq!(specs.def.collateral_token, t.token_id);
+    assert_eq!(specs.def.symbol, symbol_short!("SPY"));
+    assert_eq!(specs.def.strikes, vec![&t.env, 100]);
+    assert_eq!(specs.def.decimals, 2);
+    assert_eq!(specs.def.exp.timestamp, 1000);
+    assert_eq!(specs.def.opt_type, PUT | EUROPEAN);
+    assert_eq!(specs.def.oracle, t.oracle_id);
+    assert_eq!(specs.state, State::Listed);
+    assert!(t.client.try_specs(&(series + 1)).is_err());
+
+    t.client.trade(&series, &t.seller, &t.token_id, &SIDE_SELL, &10, &2, &10, &1);
+    assert_eq!(t.client.specs(&series).state, State::HalfFilled);
+    t.client.trade(&series, &t.buyer, &t.token_id, &SIDE_BUY, &10, &2, &10, &1);
+    assert_eq!(t.client.specs(&series).state, State::Matched);
+    t.env.ledger().with_mut(|li| li.timestamp = 1000);
+    assert_eq!(t.client.specs(&series).state, State::Expired);
+}
+
+#[test]
+fn test_multiple_series() {
+    let t = setup();
+
+    // List two strikes in the same contract
+    let s1 = t.list(PUT | EUROPEAN, vec![&t.env, 100]);
+    let s2 = t.list(PUT | EUROPEAN, vec![&t.env, 120]);
+    assert_ne!(s1, s2);
+
+    // Trade both series, the series keep separate books
+    t.client.trade(&s1, &t.seller, &t.token_id, &SIDE_SELL, &10, &2, &10, &0);
+    t.client.trade(&s1, &t.buyer, &t.token_id, &SIDE_BUY, &10, &2, &10, &0);
+    t.client.trade(&s2, &t.seller, &t.token_id, &SIDE_SELL, &20, &2, &10, &0);
+    t.client.trade(&s2, &t.buyer, &t.token_id, &SIDE_BUY, &20, &2, &10, &0);
+
+    assert_eq!(t.token.balance(&t.seller), 10_000 - 900 - 1000);
+    assert_eq!(t.token.balance(&t.buyer), 10_000 - 100 - 200);
+    assert_eq!(t.token.balance(&t.contract_id), 2200);
+}
+
+#[test]
+fn test_call_collateral() {
+    let t = setup();
+
+    // A call needs a cap above its strike
+    assert_eq!(
+        t.client.try_list(&(CALL | EUROPEAN), &vec![&t.env, 100], &2, &1000, &t.oracle_id, &t.token_id, &t.admin, &0, &100, &symbol_short!("SPY")),
+        Err(Ok(Error::InvalidInput))
+    );
+    assert_eq!(
+        t.client.try_list(&(CALL | EUROPEAN), &vec![&t.env, 100, 100], &2, &1000, &t.oracle_id, &t.token_id, &t.admin, &0, &100, &symbol_short!("SPY")),
+        Err(Ok(Error::InvalidInput))
+    );
+    let series = t.list(CALL | EUROPEAN, vec![&t.env, 100, 200]);
+    assert_eq!(t.client.specs(&series).def.strikes, vec![&t.env, 100, 200]);
+
+    // The call pays at most the cap less the strike, the seller posts that less the premium
+    t.client.trade(&series, &t.seller, &t.token_id, &SIDE_SELL, &15, &2, &10, &0);
+    t.client.trade(&series, &t.buyer, &t.token_id, &SIDE_BUY, &15, &2, &10, &0);
+
+    assert_eq!(t.token.balance(&t.seller), 10_000 - 850);
+    assert_eq!(t.token.balance(&t.buyer), 10_000 - 150);
+    assert_eq!(t.token.balance(&t.contract_id), 1000);
+}
+
+#[test]
+fn test_spreads() {
+    let t = setup();
+
+    // Spreads need two ascending strikes
+    assert!(t.client
+        .try_list(&(PUT_SPRD | EUROPEAN), &vec![&t.env, 100], &2, &1000, &t.oracle_id, &t.token_id, &t.admin, &0, &100, &symbol_short!("SPY"))
+        .is_err());
+    assert!(t.client
+        .try_list(&(PUT_SPRD | EUROPEAN), &vec![&t.env, 100, 90], &2, &1000, &t.oracle_id, &t.token_id, &t.admin, &0, &100, &symbol_short!("SPY"))
+        .is_err());
+    t.list(CALL_SPRD | EUROPEAN, vec![&t.env, 100, 110]);
+    let series = t.list(PUT_SPRD | EUROPEAN, vec![&t.env, 90, 100]);
+
+    // The seller posts the spread width less the premium
+    t.client.trade(&series, &t.seller, &t.token_id, &SIDE_SELL, &4, &2, &10, &0);
+    t.client.trade(&series, &t.buyer, &t.token_id, &SIDE_BUY, &4, &2, &10, &0);
+
+    assert_eq!(t.token.balance(&t.seller), 10_000 - 60);
+    assert_eq!(t.token.balance(&t.buyer), 10_000 - 40);
+    assert_eq!(t.token.balance(&t.contract_id), 100);
+}
+
+#[test]
+fn test_butterfly_condor() {
+    let t = setup();
+
+    // Butterflies take three strikes and condors four
+    assert!(t.client
+        .try_list(&(BUTTERFLY | EUROPEAN), &vec![&t.env, 90, 100], &2, &1000, &t.oracle_id, &t.token_id, &t.admin, &0, &100, &symbol_short!("SPY"))
+        .is_err());
+    assert!(t.client
+        .try_list(&(CONDOR | EUROPEAN), &vec![&t.env, 90, 100, 100, 110], &2, &1000, &t.oracle_id, &t.token_id, &t.admin, &0, &100, &symbol_short!("SPY"))
+        .is_err());
+    let fly = t.list(BUTTERFLY | EUROPEAN, vec![&t.env, 90, 100, 110]);
+    let condor = t.list(CONDOR | EUROPEAN, vec![&t.env, 80, 90, 110, 125]);
+
+    // The fly is worth at most 10, the condor at most its wider wing of 15
+    t.client.trade(&fly, &t.seller, &t.token_id, &SIDE_SELL, &3, &2, &10, &0);
+    t.client.trade(&fly, &t.buyer, &t.token_id, &SIDE_BUY, &3, &2, &10, &0);
+    t.client.trade(&condor, &t.seller, &t.token_id, &SIDE_SELL, &5, &2, &10, &0);
+    t.client.trade(&condor, &t.buyer, &t.token_id, &SIDE_BUY, &5, &2, &10, &0);
+
+    assert_eq!(t.token.balance(&t.seller), 10_000 - 70 - 100);
+    assert_eq!(t.token.balance(&t.buyer), 10_000 - 30 - 50);
+    assert_eq!(t.token.balance(&t.contract_id), 250);
+}
+
+#[test]
+fn test_binary() {
+    let t = setup();
+
+    // A binary needs a payout, other option types must not have one
+    assert!(t.client
+        .try_list(&(BINARY | CALL | EUROPEAN), &vec![&t.env, 100], &2, &1000, &t.oracle_id, &t.token_id, &t.admin, &0, &100, &symbol_short!("SPY"))
+        .is_err());
+    assert!(t.client
+        .try_list(&(CALL | EUROPEAN), &vec![&t.env, 100, 200], &2, &1000, &t.oracle_id, &t.token_id, &t.admin, &25, &100, &symbol_short!("SPY"))
+        .is_err());
+    let series = t.client.list(&(BINARY | PUT | EUROPEAN), &vec![&t.env, 100], &2, &1000, &t.oracle_id, &t.token_id, &t.admin, &25, &100, &symbol_short!("SPY"));
+
+    // The seller posts the payout less the premium, the buyer the premium
+    t.client.trade(&series, &t.seller, &t.token_id, &SIDE_SELL, &10, &2, &10, &0);
+    t.client.trade(&series, &t.buyer, &t.token_id, &SIDE_BUY, &10, &2, &10, &0);
+
+    assert_eq!(t.token.balance(&t.seller), 10_000 - 150);
+    assert_eq!(t.token.balance(&t.buyer), 10_000 - 100);
+    assert_eq!(t.token.balance(&t.contract_id), 250);
+}
+
+#[test]
+fn test_american_listing() {
+    let t = setup();
+
+    // Only plain puts and calls can be american
+    assert!(t.client
+        .try_list(&(PUT_SPRD | AMERICAN), &vec![&t.env, 90, 100], &2, &1000, &t.oracle_id, &t.token_id, &t.admin, &0, &100, &symbol_short!("SPY"))
+        .is_err());
+    t.list(CALL | AMERICAN, vec![&t.env, 100, 200]);
+
+    // European options can't be exercised early
+    let series = t.list(PUT | EUROPEAN, vec![&t.env, 100]);
+    assert!(t.client.try_exercise(&series, &t.buyer, &1).is_err());
+}
+
+#[test]
+fn test_trade_lifecycle() {
+    let mut t = setup();
+    t.env.budget().reset_unlimited();
+    let oracle = t.create_oracle();
+
+    let series = t.list(PUT | EUROPEAN, vec![&t.env, 100]);
+
+    // A quantity too large for the collateral to fit is rejected
+    assert_eq!(
+        t.client.try_trade(&series, &t.seller, &t.token_id, &SIDE_SELL, &10, &2, &(i128::MAX / 50), &7),
+        Err(Ok(Error::InvalidInput))
+    );
+
+    // The second side has to match the first side's trade
+    t.client.trade(&series, &t.seller, &t.token_id, &SIDE_SELL, &10, &2, &10, &7);
+    assert_eq!(t.client.try_mtm(&series, &t.seller), Err(Ok(Error::InvalidState)));
+    assert_eq!(t.client.try_mtm(&42, &t.buyer), Err(Ok(Error::InvalidInput)));
+    assert_eq!(
+        t.client.try_trade(&series, &t.buyer, &t.token_id, &SIDE_BUY, &11, &2, &10, &7),
+        Err(Ok(Error::InvalidInput))
+    );
+    assert_eq!(
+        t.client.try_trade(&series, &t.buyer, &t.token_id, &SIDE_BUY, &10, &2, &9, &7),
+        Err(Ok(Error::InvalidInput))
+    );
+    assert_eq!(
+        t.client.try_trade(&series, &t.buyer, &t.token_id, &SIDE_BUY, &10, &2, &10, &8),
+        Err(Ok(Error::InvalidInput))
+    );
+    assert_eq!(
+        t.client.try_trade(&series, &t.buyer, &t.token_id, &SIDE_BUY, &10, &3, &10, &7),
+        Err(Ok(Error::DecimalsMismatch))
+    );
+    assert_eq!(
+        t.client.try_trade(&series, &t.seller, &t.token_id, &SIDE_SELL, &10, &2, &10, &7),
+        Err(Ok(Error::DepositExists))
+    );
+    t.client.trade(&series, &t.buyer, &t.token_id, &SIDE_BUY, &10, &2, &10, &7);
+
+    let info = t.client.info(&series);
+    assert_eq!(info.buyer, Party::Acct(t.buyer.clone()));
+    assert_eq!(info.seller, Party::Acct(t.seller.clone()));
+    assert_eq!(info.buyer_dep, 100);
+    assert_eq!(info.seller_dep, 900);
+    assert_eq!(info.balance, 1000);
+    assert_eq!(info.trade_id, 7);
+    assert_eq!(info.state, State::Matched);
+
+    // No price from the oracle yet for the series' underlying
+    t.env.ledger().with_mut(|li| li.timestamp = 500);
+    oracle.update(&symbol_short!("QQQ"), &80, &400, &4, &2);
+    assert_eq!(t.client.try_upd_px(&series, &t.buyer), Err(Ok(Error::OracleNotFinal)));
+
+    // Only the admin and the accounts that traded the series can pull prices
+    let stranger = Address::random(&t.env);
+    assert_eq!(t.client.try_upd_px(&series, &stranger), Err(Ok(Error::InvalidInput)));
+    assert_eq!(t.client.try_mtm(&series, &stranger), Err(Ok(Error::InvalidInput)));
+
+    // Mark to market at 80, the put is worth 20 to the buyer and costs the seller as much
+    oracle.update(&symbol_short!("SPY"), &80, &500, &4, &2);
+    assert_eq!(t.client.mtm(&series, &t.buyer), vec![&t.env, 100, 0, 200, 0]);
+    assert_eq!(t.client.mtm(&series, &t.seller), vec![&t.env, 0, 900, 0, 800]);
+
+    // Settle at 85 after the expiration
+    t.env.ledger().with_mut(|li| li.timestamp = 1000);
+    assert_eq!(t.client.specs(&series).state, State::Expired);
+    assert_eq!(t.client.try_settle(&series, &t.buyer), Err(Ok(Error::OracleNotFinal)));
+    assert_eq!(
+        t.client.try_trade(&series, &t.buyer, &t.token_id, &SIDE_BUY, &10, &2, &10, &8),
+        Err(Ok(Error::Expired))
+    );
+
+    // A regular price after the expiration doesn't let either side cancel
+    oracle.update(&symbol_short!("SPY"), &85, &1000, &4, &2);
+    t.client.upd_px(&series, &t.buyer);
+    assert_eq!(t.client.info(&series).state, State::Expired);
+    assert_eq!(t.client.try_cancel_unmatched(&series, &t.seller), Err(Ok(Error::InvalidState)));
+    assert_eq!(t.client.try_cancel_unmatched(&series, &t.buyer), Err(Ok(Error::InvalidState)));
+    assert_eq!(t.token.balance(&t.contract_id), 1000);
+
+    // Nor does a pre-market price, only the official close settles
+    oracle.update(&symbol_short!("SPY"), &86, &1000, &1, &2);
+    t.client.upd_px(&series, &t.buyer);
+    assert_eq!(t.client.info(&series).state, State::Expired);
+
+    oracle.update(&symbol_short!("SPY"), &85, &1000, &34, &2);
+    t.client.upd_px(&series, &t.admin);
+    let info = t.client.info(&series);
+    assert_eq!((info.mkt_price, info.oracle_ts, info.oracle_flags), (85, 1000, 34));
+    assert_eq!(t.client.specs(&series).state, State::SettlementPriced);
+    assert_eq!(t.client.try_upd_px(&series, &t.buyer), Err(Ok(Error::InvalidState)));
+    t.client.settle(&series, &t.buyer);
+    assert_eq!(t.client.try_settle(&series, &t.buyer), Err(Ok(Error::InvalidState)));
+    assert_eq!(t.client.info(&series).buyer_dep, 0);
+    assert_eq!(t.client.info(&series).balance, 850);
+    t.client.settle(&series, &t.seller);
+
+    assert_eq!(t.token.balance(&t.buyer), 10_000 - 100 + 150);
+    assert_eq!(t.token.balance(&t.seller), 10_000 - 900 + 850);
+    assert_eq!(t.token.balance(&t.contract_id), 0);
+
+    let info = t.client.info(&series);
+    assert_eq!(info.balance, 0);
+    assert_eq!(info.mkt_price, 85);
+    assert_eq!(info.oracle_ts, 1000);
+    assert_eq!(info.oracle_flags, 34);
+    assert_eq!(info.state, State::Settled);
+    assert_eq!(t.client.try_settle(&series, &t.seller), Err(Ok(Error::InvalidState)));
+}
+
+#[test]
+fn test_expired_event() {
+    let mut t = setup();
+    t.env.budget().reset_unlimited();
+    let oracle = t.create_oracle();
+
+    // A series priced straight from the official close still passes through
+    // Expired, and that transition is published
+    let series = t.list(PUT | EUROPEAN, vec![&t.env, 100]);
+    t.client.trade(&series, &t.seller, &t.token_id, &SIDE_SELL, &10, &2, &10, &1);
+    t.client.trade(&series, &t.buyer, &t.token_id, &SIDE_BUY, &10, &2, &10, &1);
+    let half = t.list(PUT | EUROPEAN, vec![&t.env, 100]);
+    t.client.trade(&half, &t.seller, &t.token_id, &SIDE_SELL, &10, &2, &10, &1);
+    t.env.ledger().with_mut(|li| li.timestamp = 1000);
+    oracle.update(&symbol_short!("SPY"), &85, &1000, &34, &2);
+    t.client.upd_px(&series, &t.buyer);
+    assert_eq!(
+        states(&t, series),
+        [State::Listed, State::HalfFilled, State::Matched, State::Expired, State::SettlementPriced]
+    );
+
+    // A half filled series that expired is published as Expired by the cancel
+    t.client.cancel_unmatched(&half, &t.seller);
+    assert_eq!(states(&t, half), [State::Listed, State::HalfFilled, State::Expired]);
+}
+
+#[test]
+fn test_american_exercise() {
+    let mut t = setup();
+    t.env.budget().reset_unlimited();
+    let oracle = t.create_oracle();
+
+    let series = t.list(PUT | AMERICAN, vec![&t.env, 100]);
+    t.client.trade(&series, &t.seller, &t.token_id, &SIDE_SELL, &10, &2, &10, &1);
+    t.client.trade(&series, &t.buyer, &t.token_id, &SIDE_BUY, &10, &2, &10, &1);
+
+    // Exercise needs a regular session price
+    t.env.ledger().with_mut(|li| li.timestamp = 500);
+    oracle.update(&symbol_short!("SPY"), &70, &500, &2, &2);
+    assert!(t.client.try_exercise(&series, &t.buyer, &4).is_err());
+    oracle.update(&symbol_short!("SPY"), &70, &500, &4, &2);
+    assert!(t.client.try_exercise(&series, &t.seller, &4).is_err());
+    assert!(t.client.try_exercise(&series, &t.buyer, &11).is_err());
+    t.client.exercise(&series, &t.buyer, &4);
+    assert_eq!(t.token.balance(&t.buyer), 10_000 - 100 + 120);
+    assert_eq!(t.client.position(&series, &t.buyer).pos, 6);
+    assert_eq!(t.client.position(&series, &t.seller).pos, -6);
+
+    // Only what is left of the positions is marked to market
+    assert_eq!(t.client.mtm(&series, &t.buyer), vec![&t.env, 60, 0, 180, 0]);
+    assert_eq!(t.client.mtm(&series, &t.seller), vec![&t.env, 0, 540, 0, 420]);
+
+    // The rest settles at expiration
+    t.env.ledger().with_mut(|li| li.timestamp = 1000);
+    assert!(t.client.try_exercise(&series, &t.buyer, &1).is_err());
+    oracle.update(&symbol_short!("SPY"), &90, &1000, &34, &2);
+    t.client.upd_px(&series, &t.buyer);
+    t.client.settle(&series, &t.buyer);
+    t.client.settle(&series, &t.seller);
+
+    assert_eq!(t.token.balance(&t.buyer), 10_000 - 100 + 120 + 60);
+    assert_eq!(t.token.balance(&t.seller), 10_000 - 900 + 820);
+    assert_eq!(t.token.balance(&t.contract_id), 0);
+}
+
+#[test]
+fn test_call_settlement() {
+    let mut t = setup();
+    t.env.budget().reset_unlimited();
+    let oracle = t.create_oracle();
+
+    let series = t.list(CALL | EUROPEAN, vec![&t.env, 100, 150]);
+    t.client.trade(&series, &t.seller, &t.token_id, &SIDE_SELL, &10, &2, &10, &1);
+    t.client.trade(&series, &t.buyer, &t.token_id, &SIDE_BUY, &10, &2, &10, &1);
+    assert_eq!(t.token.balance(&t.seller), 10_000 - 400);
+
+    // Settled far above the cap the call pays the cap less the strike
+    t.env.ledger().with_mut(|li| li.timestamp = 1000);
+    oracle.update(&symbol_short!("SPY"), &300, &1000, &34, &2);
+    t.client.upd_px(&series, &t.buyer);
+    t.client.settle(&series, &t.buyer);
+
+    // which takes all of the seller's collateral
+    assert_eq!(t.client.specs(&series).state, State::Settled);
+    assert_eq!(t.token.balance(&t.buyer), 10_000 - 100 + 500);
+    assert_eq!(t.token.balance(&t.seller), 10_000 - 400);
+    assert_eq!(t.token.balance(&t.contract_id), 0);
+}
+
+#[test]
+fn test_cancel_unmatched() {
+    let t = setup();
+
+    let series = t.list(PUT | EUROPEAN, vec![&t.env, 100]);
+    t.env.ledger().with_mut(|li| li.timestamp = 50);
+    t.client.trade(&series, &t.seller, &t.token_id, &SIDE_SELL, &10, &2, &10, &1);
+    assert_eq!(t.client.info(&series).balance, 900);
+
+    // The seller has to wait for the match window to pass
+    t.env.ledger().with_mut(|li| li.timestamp = 149);
+    assert!(t.client.try_cancel_unmatched(&series, &t.seller).is_err());
+    t.env.ledger().with_mut(|li| li.timestamp = 150);
+    assert!(t.client.try_cancel_unmatched(&series, &t.buyer).is_err());
+    assert_eq!(
+        t.client.try_trade(&series, &t.buyer, &t.token_id, &SIDE_BUY, &10, &2, &10, &1),
+        Err(Ok(Error::InvalidState))
+    );
+    t.client.cancel_unmatched(&series, &t.seller);
+    assert_eq!(t.token.balance(&t.seller), 10_000);
+    assert_eq!(t.client.specs(&series).state, State::Cancelled);
+    assert_eq!(t.client.info(&series).balance, 0);
+    t.env.ledger().with_mut(|li| li.timestamp = 1000);
+    assert_eq!(t.client.try_settle(&series, &t.seller), Err(Ok(Error::InvalidState)));
+    t.env.ledger().with_mut(|li| li.timestamp = 150);
+    assert!(t.client.try_cancel_unmatched(&series, &t.seller).is_err());
+
+    // The series can be traded again, and a matched trade can't be cancelled
+    t.client.trade(&series, &t.buyer, &t.token_id, &SIDE_BUY, &12, &2, &10, &2);
+    t.client.trade(&series, &t.seller, &t.token_id, &SIDE_SELL, &12, &2, &10, &2);
+    t.env.ledger().with_mut(|li| li.timestamp = 500);
+    assert!(t.client.try_cancel_unmatched(&series, &t.buyer).is_err());
+    assert_eq!(t.token.balance(&t.contract_id), 1000);
+
+    // A match window longer than the series runs until the expiration
+    let open = t.client.list(&(PUT | EUROPEAN), &vec![&t.env, 100], &2, &1000, &t.oracle_id, &t.token_id, &t.admin, &0, &u64::MAX, &symbol_short!("SPY"));
+    t.client.trade(&open, &t.seller, &t.token_id, &SIDE_SELL, &10, &2, &1, &3);
+    t.env.ledger().with_mut(|li| li.timestamp = 999);
+    t.client.trade(&open, &t.buyer, &t.token_id, &SIDE_BUY, &10, &2, &1, &3);
+    assert_eq!(t.client.specs(&open).state, State::Matched);
+}
+
+#[test]
+fn test_positions() {
+    let t = setup();
+
+    let s1 = t.list(PUT | EUROPEAN, vec![&t.env, 100]);
+    let s2 = t.list(CALL | EUROPEAN, vec![&t.env, 100, 200]);
+
+    // Positions are only booked once the trade is matched
+    t.client.trade(&s1, &t.seller, &t.token_id, &SIDE_SELL, &10, &2, &10, &1);
+    assert_eq!(t.client.positions(&s1).len(), 0);
+    t.client.trade(&s1, &t.buyer, &t.token_id, &SIDE_BUY, &10, &2, &10, &1);
+    t.client.trade(&s2, &t.buyer, &t.token_id, &SIDE_SELL, &10, &2, &3, &1);
+    t.client.trade(&s2, &t.seller, &t.token_id, &SIDE_BUY, &10, &2, &3, &1);
+
+    let position = t.client.position(&s1, &t.buyer);
+    assert_eq!(position.pos, 10);
+    assert_eq!(position.acct, t.buyer);
+    assert_eq!(position.token, t.token_id);
+    assert_eq!(t.client.position(&s1, &t.seller).pos, -10);
+    assert_eq!(t.client.position(&s2, &t.buyer).pos, -3);
+    assert_eq!(t.client.position(&s2, &t.seller).pos, 3);
+    assert_eq!(t.client.position(&s2, &t.admin).pos, 0);
+
+    let positions = t.client.positions(&s1);
+    assert_eq!(positions.len(), 2);
+    assert_eq!(positions.get(0).unwrap().acct, t.buyer);
+    assert_eq!(positions.get(1).unwrap().pos, -10);
+
+    // Adding to a position averages its price, reducing it keeps the price
+    let other = t.account();
+    t.client.transfer_position(&s1, &t.buyer, &other, &4, &16);
+    t.client.transfer_position(&s1, &t.buyer, &other, &2, &12);
+    assert_eq!(t.client.position(&s1, &other).price, 14);
+    assert_eq!(t.client.position(&s1, &t.buyer).price, 10);
+    assert_eq!(t.client.position(&s1, &t.seller).price, 10);
+}
+
+#[test]
+fn test_trade_history() {
+    let t = setup();
+
+    let series = t.list(PUT | EUROPEAN, vec![&t.env, 100]);
+
+    // Only matched trades are logged
+    t.env.ledger().with_mut(|li| li.timestamp = 10);
+    t.client.trade(&series, &t.seller, &t.token_id, &SIDE_SELL, &10, &2, &10, &42);
+    assert_eq!(t.client.trades(&series, &0, &10).len(), 0);
+    assert!(t.client.try_get_trade(&series, &42).is_err());
+    t.env.ledger().with_mut(|li| li.timestamp = 20);
+    t.client.trade(&series, &t.buyer, &t.token_id, &SIDE_BUY, &10, &2, &10, &42);
+
+    let trd = t.client.get_trade(&series, &42);
+    assert_eq!(trd.price, 10);
+    assert_eq!(trd.decimals, 2);
+    assert_eq!(trd.qty, 10);
+    assert_eq!(trd.buyer, t.buyer);
+    assert_eq!(trd.seller, t.seller);
+    assert_eq!(trd.date_time, 20);
+    assert_eq!(trd.trade_id, 42);
+
+    assert_eq!(t.client.trades(&series, &0, &10).len(), 1);
+    assert_eq!(t.client.trades(&series, &0, &0).len(), 0);
+    assert_eq!(t.client.trades(&series, &1, &10).len(), 0);
+}
+
+#[test]
+fn test_transfer_position() {
+    let mut t = setup();
+    t.env.budget().reset_unlimited();
+    let oracle = t.create_oracle();
+    let new_buyer = t.account();
+    let new_seller = t.account();
+
+    let series = t.list(PUT | EUROPEAN, vec![&t.env, 100]);
+    t.client.trade(&series, &t.seller, &t.token_id, &SIDE_SELL, &10, &2, &10, &1);
+    t.client.trade(&series, &t.buyer, &t.token_id, &SIDE_BUY, &10, &2, &10, &1);
+
+    // Part of the long position changes hands, the new buyer pays the old one
+    assert!(t.client.try_transfer_position(&series, &t.buyer, &new_buyer, &11, &15).is_err());
+    t.client.transfer_position(&series, &t.buyer, &new_buyer, &4, &15);
+    assert_eq!(t.token.balance(&t.buyer), 10_000 - 100 + 60);
+    assert_eq!(t.token.balance(&new_buyer), 10_000 - 60);
+
+    // The new seller posts collateral and the old seller gets it back
+    t.client.transfer_position(&series, &t.seller, &new_seller, &6, &12);
+    assert_eq!(t.token.balance(&t.seller), 10_000 - 900 + 528);
+    assert_eq!(t.token.balance(&new_seller), 10_000 - 528);
+    assert_eq!(t.token.balance(&t.contract_id), 1000);
+
+    assert_eq!(t.client.position(&series, &t.buyer).pos, 6);
+    assert_eq!(t.client.position(&series, &new_buyer).pos, 4);
+    assert_eq!(t.client.position(&series, &t.seller).pos, -4);
+    assert_eq!(t.client.position(&series, &new_seller).pos, -6);
+    assert_eq!(t.client.trades(&series, &0, &10).len(), 3);
+
+    // Transfers are in the history but can't be looked up by trade id
+    let trds = t.client.trades(&series, &1, &10);
+    assert_eq!(trds.len(), 2);
+    assert_eq!(trds.get_unchecked(0).buyer, new_buyer);
+    assert_eq!(trds.get_unchecked(1).seller, new_seller);
+    assert_eq!(t.client.get_trade(&series, &1).buyer, t.buyer);
+    assert!(t.client.try_get_trade(&series, &0).is_err());
+
+    // Each account is marked to market at the price it traded at
+    t.env.ledger().with_mut(|li| li.timestamp = 500);
+    oracle.update(&symbol_short!("SPY"), &80, &500, &4, &2);
+    assert_eq!(t.client.position(&series, &new_buyer).price, 15);
+    assert_eq!(t.client.mtm(&series, &t.buyer), vec![&t.env, 60, 0, 120, 0]);
+    assert_eq!(t.client.mtm(&series, &new_buyer), vec![&t.env, 60, 0, 80, 0]);
+    assert_eq!(t.client.mtm(&series, &t.seller), vec![&t.env, 0, 360, 0, 320]);
+    assert_eq!(t.client.mtm(&series, &new_seller), vec![&t.env, 0, 528, 0, 480]);
+
+    // Everybody settles off their own position at 80
+    t.env.ledger().with_mut(|li| li.timestamp = 1000);
+    assert!(t.client.try_transfer_position(&series, &t.buyer, &new_buyer, &1, &15).is_err());
+    oracle.update(&symbol_short!("SPY"), &80, &1000, &34, &2);
+    t.client.upd_px(&series, &t.buyer);
+    for acct in [&t.buyer, &t.seller, &new_buyer, &new_seller] {
+        t.client.settle(&series, acct);
+    }
+
+    assert_eq!(t.token.balance(&t.buyer), 10_000 - 100 + 60 + 120);
+    assert_eq!(t.token.balance(&new_buyer), 10_000 - 60 + 80);
+    assert_eq!(t.token.balance(&t.seller), 10_000 - 900 + 528 + 320);
+    assert_eq!(t.token.balance(&new_seller), 10_000 - 528 + 480);
+    assert_eq!(t.token.balance(&t.contract_id), 0);
+}
+
+#[test]
+fn test_netting() {
+    let t = setup();
+    let market_maker = t.account();
+
+    // Crossing both sides of a trade locks nothing
+    let s1 = t.list(PUT | EUROPEAN, vec![&t.env, 100]);
+    t.client.trade(&s1, &market_maker, &t.token_id, &SIDE_SELL, &10, &2, &10, &1);
+    t.client.trade(&s1, &market_maker, &t.token_id, &SIDE_BUY, &10, &2, &10, &1);
+    assert_eq!(t.client.position(&s1, &market_maker).pos, 0);
+    assert_eq!(t.token.balance(&market_maker), 10_000);
+    assert_eq!(t.token.balance(&t.contract_id), 0);
+
+    // The market maker buys from the seller and then takes over the buyer's
+    // short, the collateral of the offsetting options is released
+    let s2 = t.list(PUT | EUROPEAN, vec![&t.env, 100]);
+    t.client.trade(&s2, &t.seller, &t.token_id, &SIDE_SELL, &10, &2, &10, &1);
+    t.client.trade(&s2, &market_maker, &t.token_id, &SIDE_BUY, &10, &2, &10, &1);
+    t.client.transfer_position(&s2, &t.seller, &t.buyer, &10, &10);
+    t.client.transfer_position(&s2, &market_maker, &t.buyer, &4, &12);
+    assert_eq!(t.client.position(&s2, &t.buyer).pos, -6);
+    assert_eq!(t.token.balance(&t.buyer), 10_000 - 900 - 48 + 400);
+    t.client.transfer_position(&s2, &t.buyer, &market_maker, &6, &15);
+    assert_eq!(t.client.position(&s2, &market_maker).pos, 0);
+    assert_eq!(t.token.balance(&market_maker), 10_000 - 100 + 48 - 510 + 600);
+    assert_eq!(t.token.balance(&t.buyer), 10_000 - 900 - 48 + 400 + 510);
+    assert_eq!(t.token.balance(&t.contract_id), 0);
+
+    // Nothing left to release on demand
+    assert_eq!(t.client.net(&s2, &market_maker), 0);
+}
+
+#[test]
+fn test_stale_quotes() {
+    let mut t = setup();
+    t.env.budget().reset_unlimited();
+    let oracle = t.create_oracle();
+
+    let series = t.list(PUT | EUROPEAN, vec![&t.env, 100]);
+    let other = t.list(PUT | EUROPEAN, vec![&t.env, 100]);
+    assert_eq!(t.client.specs(&series).def.max_staleness, 3600);
+    assert_eq!(t.client.try_set_staleness(&series, &0), Err(Ok(Error::InvalidInput)));
+    t.client.set_staleness(&series, &300);
+    t.client.set_staleness(&other, &300);
+    assert_eq!(t.client.specs(&series).def.max_staleness, 300);
+    t.client.trade(&series, &t.seller, &t.token_id, &SIDE_SELL, &10, &2, &10, &1);
+    t.client.trade(&series, &t.buyer, &t.token_id, &SIDE_BUY, &10, &2, &10, &1);
+
+    // Mark to market only against a quote taken within the last 300 seconds
+    t.env.ledger().with_mut(|li| li.timestamp = 600);
+    oracle.update(&symbol_short!("SPY"), &80, &200, &4, &2);
+    assert_eq!(t.client.try_mtm(&series, &t.buyer), Err(Ok(Error::OracleNotFinal)));
+    oracle.update(&symbol_short!("SPY"), &80, &400, &4, &2);
+    assert_eq!(t.client.mtm(&series, &t.buyer), vec![&t.env, 100, 0, 200, 0]);
+    // nor against one stamped ahead of the ledger
+    oracle.update(&symbol_short!("SPY"), &80, &601, &4, &2);
+    assert_eq!(t.client.try_mtm(&series, &t.buyer), Err(Ok(Error::OracleNotFinal)));
+
+    // A settlement price taken before the expiration doesn't count
+    t.env.ledger().with_mut(|li| li.timestamp = 1000);
+    oracle.update(&symbol_short!("SPY"), &85, &900, &34, &2);
+    assert_eq!(t.client.try_upd_px(&series, &t.buyer), Err(Ok(Error::OracleNotFinal)));
+
+    // One taken within 300 seconds of the expiration does, however late the
+    // series picks it up
+    t.env.ledger().with_mut(|li| li.timestamp = 5000);
+    oracle.update(&symbol_short!("SPY"), &85, &1200, &34, &2);
+    t.client.upd_px(&series, &t.buyer);
+    assert_eq!(t.client.specs(&series).state, State::SettlementPriced);
+    t.client.settle(&series, &t.buyer);
+    t.client.settle(&series, &t.seller);
+    assert_eq!(t.token.balance(&t.buyer), 10_000 - 100 + 150);
+    assert_eq!(t.client.try_set_staleness(&series, &600), Err(Ok(Error::InvalidState)));
+
+    // and one taken later than that doesn't
+    oracle.update(&symbol_short!("SPY"), &85, &1400, &34, &2);
+    assert_eq!(t.client.try_upd_px(&other, &t.admin), Err(Ok(Error::OracleNotFinal)));
+}
+
+#[test]
+fn test_oracle_fee() {
+    let mut t = setup();
+    t.env.budget().reset_unlimited();
+    let oracle = t.create_oracle();
+
+    let series = t.list(PUT | EUROPEAN, vec![&t.env, 100]);
+    t.client.trade(&series, &t.seller, &t.token_id, &SIDE_SELL, &10, &2, &10, &1);
+    t.client.trade(&series, &t.buyer, &t.token_id, &SIDE_BUY, &10, &2, &10, &1);
+    t.env.ledger().with_mut(|li| li.timestamp = 500);
+    oracle.update(&symbol_short!("SPY"), &80, &500, &4, &2);
+
+    // Whoever pulls a price pays the oracle's fee for it
+    oracle.set_fee(&t.token_id, &3);
+    t.client.upd_px(&series, &t.buyer);
+    assert_eq!(t.token.balance(&t.buyer), 10_000 - 100 - 3);
+    t.client.mtm(&series, &t.seller);
+    assert_eq!(t.token.balance(&t.seller), 10_000 - 900 - 3);
+    assert_eq!(t.token.balance(&t.oracle_id), 6);
+    assert_eq!(oracle.credit_of(&t.contract_id), 0);
+
+    // The series admin has nothing to pay the fee with
+    assert_eq!(t.client.try_upd_px(&series, &t.admin), Err(Ok(Error::OracleFee)));
+
+    // Credit prepaid for the contract is spent before the caller pays
+    oracle.deposit_credit(&t.buyer, &t.contract_id, &4);
+    t.client.upd_px(&series, &t.admin);
+    assert_eq!(oracle.credit_of(&t.contract_id), 1);
+    t.client.upd_px(&series, &t.seller);
+    assert_eq!(t.token.balance(&t.seller), 10_000 - 900 - 3 - 2);
+    assert_eq!(oracle.credit_of(&t.contract_id), 0);
 }
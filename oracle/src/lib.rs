@@ -3,12 +3,29 @@
 #[contract]
 struct OracleContract;
 
-use soroban_sdk::{contract, contractimpl, contracttype, Address, BytesN, Env, Symbol, Vec};
+use soroban_sdk::{contract, contracterror, contractimpl, contracttype, token, Address, BytesN, Env, Symbol, Vec};
 
-#[derive(Clone)]
+const MAX_DECIMALS: u32 = 18; // Most decimals a price can have
+
+#[contracterror]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, PartialOrd, Ord)]
+#[repr(u32)]
+pub enum Error {
+    NotInitialized = 1,     // init has not been called
+    AlreadyInitialized = 2, // init has already been called
+    NoQuote = 3,            // No price has been pushed for the instrument yet
+    Unauthorized = 4,       // Caller is not allowed, no price pump user, quorum or admin proposed
+    StaleQuote = 5,         // Price is older than the current quote of the instrument
+    BadDecimals = 6,        // Price has more than MAX_DECIMALS decimals
+    LowCredit = 7,          // Caller's credit doesn't cover the fee of a retrieve
+    InvalidInput = 8,       // Bad fee, amount, fee token or decimals that differ within a round
+    NoFeeToken = 9,         // No fee token has been set
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
 #[contracttype]
 pub struct UpdData {
-    pub token: i128,     // Token contract, asset_code, asset_id  TBD
+    pub instrument: Symbol, // Underlying asset symbol *See SYMBOLOGY.md for details
     pub price: i128,     // Price of asset in USD
     pub timestamp: i128, // Timestamp of price
     pub flags: i128, // Flags : MktOpen, MktClosed, Settlement, Halted, Bitmask 0,1,2,4,8,16,32... TBD
@@ -19,81 +36,380 @@ pub struct UpdData {
 #[contracttype]
 pub enum DataKey {
     Init,
-    Quote,
+    Admin,          // Admin that manages the users and the configuration
+    PendingAdmin,   // Proposed admin, becomes the admin once it accepts
+    Quote(Symbol),  // Latest quote of an instrument
     PxPumpHash,     // SHA256 hash of the price pump code
     PxPumpUser,     // User for the price pump that invokes the update function
     Users(Address), // List of users that can invoke the retrieve function
-    Decimals
+    Decimals,
+    FeeToken,       // Token the fees are paid in
+    Fee,            // Fee charged for every retrieve
+    Credit(Address), // Prepaid credit of a user
+    Fees,           // Fees collected and not withdrawn yet
+    Publisher(Address), // Publishers that can submit quotes in a round
+    NPublishers,    // Number of publishers
+    Quorum,         // Submissions needed to publish the quote of a round, 0 if off
+    Round(Symbol),  // Publishers that submitted in the current round of an instrument
+    Rounds,         // Instruments with a round in progress
+    Submission(Symbol, Address), // Last submission of a publisher for an instrument
 }
 
 #[contractimpl]
 impl OracleContract {
-    pub fn init(env: Env) {
-        if is_initialized(&env) == true {
-            panic!("Contract already initialized");
+    pub fn init(env: Env, admin: Address) -> Result<(), Error> {
+        if is_initialized(&env) {
+            return Err(Error::AlreadyInitialized);
         }
+        admin.require_auth();
         env.storage().instance().set(&DataKey::Init, &true);
+        env.storage().instance().set(&DataKey::Admin, &admin);
+        Ok(())
     }
 
-    pub fn set_pxpump_user(env: Env, user: Address) {
-        if is_initialized(&env) == false {
-            panic!("Contract not initialized");
+    // Allow a user, an account or a contract, to retrieve quotes
+    pub fn add_user(env: Env, user: Address) -> Result<(), Error> {
+        check_admin(&env)?;
+        env.storage().persistent().set(&DataKey::Users(user), &true);
+        Ok(())
+    }
+
+    // Remove a user, its unused credit is refunded so it isn't stranded
+    pub fn remove_user(env: Env, user: Address) -> Result<(), Error> {
+        check_admin(&env)?;
+        env.storage().persistent().remove(&DataKey::Users(user.clone()));
+        let credit = Self::credit_of(env.clone(), user.clone());
+        if credit > 0 {
+            refund_credit(&env, &user, &user, credit);
         }
-        env.storage().instance().set(&DataKey::PxPumpUser, &user);
+        Ok(())
+    }
+
+    pub fn is_user(env: Env, user: Address) -> bool {
+        env.storage().persistent().has(&DataKey::Users(user))
+    }
+
+    // Charge `fee` units of `token` for every retrieve, 0 makes the quotes free.
+    // The fee token can't be changed once set since the credits are held in it.
+    pub fn set_fee(env: Env, token: Address, fee: i128) -> Result<(), Error> {
+        check_admin(&env)?;
+        if fee < 0 {
+            return Err(Error::InvalidInput);
+        }
+        match env.storage().instance().get::<_, Address>(&DataKey::FeeToken) {
+            Some(fee_token) if fee_token != token => return Err(Error::InvalidInput),
+            Some(_) => (),
+            None => env.storage().instance().set(&DataKey::FeeToken, &token),
+        }
+        env.storage().instance().set(&DataKey::Fee, &fee);
+        Ok(())
+    }
+
+    // Prepay the retrieves of `user`, anybody can fund a user, e.g. the
+    // users of a contract can fund it
+    pub fn deposit_credit(env: Env, from: Address, user: Address, amount: i128) -> Result<i128, Error> {
+        if !is_initialized(&env) {
+            return Err(Error::NotInitialized);
+        }
+        from.require_auth();
+        if amount <= 0 {
+            return Err(Error::InvalidInput);
+        }
+        let fee_token: Address = match env.storage().instance().get(&DataKey::FeeToken) {
+            Some(fee_token) => fee_token,
+            None => return Err(Error::NoFeeToken),
+        };
+        // Check the funds first, a failed transfer would abort the caller too
+        let token = token::Client::new(&env, &fee_token);
+        if token.balance(&from) < amount {
+            return Err(Error::LowCredit);
+        }
+        token.transfer(&from, &env.current_contract_address(), &amount);
+
+        let credit = Self::credit_of(env.clone(), user.clone()) + amount;
+        env.storage().persistent().set(&DataKey::Credit(user.clone()), &credit);
+
+        // Emit event
+        let topic = (Symbol::new(&env, "credit"), user);
+        env.events().publish(topic, credit);
+        Ok(credit)
+    }
+
+    // Refund `amount` of the user's unused credit to `to`, returns the
+    // credit left
+    pub fn withdraw_credit(env: Env, user: Address, to: Address, amount: i128) -> Result<i128, Error> {
+        user.require_auth();
+        if amount <= 0 {
+            return Err(Error::InvalidInput);
+        }
+        let credit = Self::credit_of(env.clone(), user.clone());
+        if credit < amount {
+            return Err(Error::LowCredit);
+        }
+        Ok(refund_credit(&env, &user, &to, amount))
+    }
+
+    pub fn credit_of(env: Env, user: Address) -> i128 {
+        env.storage().persistent().get(&DataKey::Credit(user)).unwrap_or(0)
+    }
+
+    // Fee charged for every retrieve, 0 if the quotes are free
+    pub fn fee(env: Env) -> i128 {
+        env.storage().instance().get(&DataKey::Fee).unwrap_or(0)
     }
 
-    pub fn set_pxpump_hash(env: Env, hash: BytesN<32>) {
-        if is_initialized(&env) == false {
-            panic!("Contract not initialized");
+    // Send the collected fees to `to`, returns the amount withdrawn
+    pub fn withdraw_fees(env: Env, to: Address) -> Result<i128, Error> {
+        check_admin(&env)?;
+        let fees: i128 = env.storage().instance().get(&DataKey::Fees).unwrap_or(0);
+        if fees > 0 {
+            let fee_token: Address = env.storage().instance().get(&DataKey::FeeToken).unwrap();
+            token::Client::new(&env, &fee_token).transfer(&env.current_contract_address(), &to, &fees);
+            env.storage().instance().set(&DataKey::Fees, &0_i128);
         }
+        Ok(fees)
+    }
+
+    // Hand the admin role over in two steps so it can't be given to an
+    // address nobody controls
+    pub fn propose_admin(env: Env, new_admin: Address) -> Result<(), Error> {
+        check_admin(&env)?;
+        env.storage().instance().set(&DataKey::PendingAdmin, &new_admin);
+        Ok(())
+    }
+
+    pub fn accept_admin(env: Env) -> Result<(), Error> {
+        if !is_initialized(&env) {
+            return Err(Error::NotInitialized);
+        }
+        let new_admin: Address = match env.storage().instance().get(&DataKey::PendingAdmin) {
+            Some(new_admin) => new_admin,
+            None => return Err(Error::Unauthorized),
+        };
+        new_admin.require_auth();
+        env.storage().instance().set(&DataKey::Admin, &new_admin);
+        env.storage().instance().remove(&DataKey::PendingAdmin);
+
+        // Emit event
+        env.events().publish((Symbol::new(&env, "admin"),), new_admin);
+        Ok(())
+    }
+
+    pub fn admin(env: Env) -> Result<Address, Error> {
+        match env.storage().instance().get(&DataKey::Admin) {
+            Some(admin) => Ok(admin),
+            None => Err(Error::NotInitialized),
+        }
+    }
+
+    pub fn set_pxpump_user(env: Env, user: Address) -> Result<(), Error> {
+        check_admin(&env)?;
+        env.storage().instance().set(&DataKey::PxPumpUser, &user);
+        Ok(())
+    }
+
+    pub fn set_pxpump_hash(env: Env, hash: BytesN<32>) -> Result<(), Error> {
+        check_admin(&env)?;
         env.storage().instance().set(&DataKey::PxPumpHash, &hash);
+        Ok(())
+    }
+
+    pub fn add_publisher(env: Env, publisher: Address) -> Result<(), Error> {
+        check_admin(&env)?;
+        if Self::is_publisher(env.clone(), publisher.clone()) {
+            return Ok(());
+        }
+        env.storage().persistent().set(&DataKey::Publisher(publisher), &true);
+        env.storage().instance().set(&DataKey::NPublishers, &(get_npublishers(&env) + 1));
+        Ok(())
     }
 
-    pub fn update(env: Env, token: i128, price: i128, timestamp: i128, flags: i128, decimals: u32) {
-        if is_initialized(&env) == false {
-            panic!("Contract not initialized");
+    // A publisher can't be removed if that leaves fewer publishers than the quorum
+    pub fn remove_publisher(env: Env, publisher: Address) -> Result<(), Error> {
+        check_admin(&env)?;
+        if !Self::is_publisher(env.clone(), publisher.clone()) {
+            return Ok(());
         }
+        let npublishers = get_npublishers(&env) - 1;
+        if npublishers < get_quorum(&env) {
+            return Err(Error::InvalidInput);
+        }
+        env.storage().persistent().remove(&DataKey::Publisher(publisher));
+        env.storage().instance().set(&DataKey::NPublishers, &npublishers);
+        Ok(())
+    }
+
+    pub fn is_publisher(env: Env, publisher: Address) -> bool {
+        env.storage().persistent().has(&DataKey::Publisher(publisher))
+    }
 
-        // TODO: Implement this check
-        let pxpump_user: Address = env.storage().instance().get(&DataKey::PxPumpUser).unwrap();
-        pxpump_user.require_auth();
+    // Once a quorum is set the quotes only come from the publishers' rounds,
+    // the price pump user can't push prices anymore. 0 goes back to the price pump.
+    // The quorum can't be more than the number of publishers, and changing it
+    // drops the rounds in progress.
+    pub fn set_quorum(env: Env, quorum: u32) -> Result<(), Error> {
+        check_admin(&env)?;
+        if quorum > get_npublishers(&env) {
+            return Err(Error::InvalidInput);
+        }
+        if quorum == get_quorum(&env) {
+            return Ok(());
+        }
+        let rounds: Option<Vec<Symbol>> = env.storage().instance().get(&DataKey::Rounds);
+        if let Some(rounds) = rounds {
+            for instrument in rounds.iter() {
+                env.storage().persistent().remove(&DataKey::Round(instrument));
+            }
+            env.storage().instance().remove(&DataKey::Rounds);
+        }
+        env.storage().instance().set(&DataKey::Quorum, &quorum);
+        Ok(())
+    }
+
+    // Submit a publisher's quote for the current round of an instrument, a new
+    // submission replaces the publisher's previous one. When `quorum` publishers
+    // have submitted, the median price is published as the quote and a new round starts.
+    pub fn submit(
+        env: Env,
+        publisher: Address,
+        instrument: Symbol,
+        price: i128,
+        timestamp: i128,
+        flags: i128,
+        decimals: u32,
+    ) -> Result<(), Error> {
+        if !is_initialized(&env) {
+            return Err(Error::NotInitialized);
+        }
+        publisher.require_auth();
+        let quorum = get_quorum(&env);
+        if quorum == 0 || !Self::is_publisher(env.clone(), publisher.clone()) {
+            return Err(Error::Unauthorized);
+        }
+        if decimals > MAX_DECIMALS {
+            return Err(Error::BadDecimals);
+        }
+        let quote: Option<UpdData> = env.storage().persistent().get(&DataKey::Quote(instrument.clone()));
+        if let Some(quote) = quote {
+            if timestamp < quote.timestamp {
+                return Err(Error::StaleQuote);
+            }
+        }
+
+        // Publishers removed since they submitted don't count
+        let round_key = DataKey::Round(instrument.clone());
+        let submitted: Vec<Address> = env.storage().persistent().get(&round_key).unwrap_or(Vec::new(&env));
+        let mut round: Vec<Address> = Vec::new(&env);
+        for member in submitted.iter() {
+            if member != publisher && Self::is_publisher(env.clone(), member.clone()) {
+                round.push_back(member);
+            }
+        }
+        // Prices of a round have to be in the same decimals
+        if let Some(member) = round.first() {
+            let first: UpdData = get_submission(&env, &instrument, &member);
+            if first.decimals != decimals {
+                return Err(Error::InvalidInput);
+            }
+        }
 
         let upd_data = UpdData {
-            token,
+            instrument: instrument.clone(),
             price,
             timestamp,
             flags,
             decimals,
         };
-        env.storage().instance().set(&DataKey::Quote, &upd_data);
+        env.storage()
+            .persistent()
+            .set(&DataKey::Submission(instrument.clone(), publisher.clone()), &upd_data);
+        round.push_back(publisher.clone());
 
         // Emit event
-        let topic = (Symbol::new(&env, "update"), token);
+        let topic = (Symbol::new(&env, "submit"), instrument.clone(), publisher);
         env.events().publish(topic, timestamp);
+
+        let mut rounds: Vec<Symbol> = env.storage().instance().get(&DataKey::Rounds).unwrap_or(Vec::new(&env));
+        let in_rounds = rounds.first_index_of(&instrument);
+        if round.len() < quorum {
+            env.storage().persistent().set(&round_key, &round);
+            if in_rounds.is_none() {
+                rounds.push_back(instrument);
+                env.storage().instance().set(&DataKey::Rounds, &rounds);
+            }
+            return Ok(());
+        }
+        env.storage().persistent().remove(&round_key);
+        if let Some(i) = in_rounds {
+            rounds.remove(i);
+            env.storage().instance().set(&DataKey::Rounds, &rounds);
+        }
+        let quote = aggregate(&env, &instrument, &round);
+        set_quote(&env, &quote)
     }
 
-    pub fn retrieve(env: Env) -> Vec<i128> {
-        if is_initialized(&env) == false {
-            panic!("Contract not initialized");
+    pub fn submission(env: Env, publisher: Address, instrument: Symbol) -> Result<UpdData, Error> {
+        match env.storage().persistent().get(&DataKey::Submission(instrument, publisher)) {
+            Some(upd_data) => Ok(upd_data),
+            None => Err(Error::NoQuote),
         }
+    }
 
-        // TODO: Check if the caller is in the list of users that can invoke this function
+    pub fn update(
+        env: Env,
+        instrument: Symbol,
+        price: i128,
+        timestamp: i128,
+        flags: i128,
+        decimals: u32,
+    ) -> Result<(), Error> {
+        check_pxpump_user(&env)?;
 
-        let upd_data: UpdData = env.storage().instance().get(&DataKey::Quote).unwrap();
+        let upd_data = UpdData {
+            instrument,
+            price,
+            timestamp,
+            flags,
+            decimals,
+        };
+        set_quote(&env, &upd_data)
+    }
+
+    // Push the quotes of several instruments in one call
+    pub fn update_many(env: Env, quotes: Vec<UpdData>) -> Result<(), Error> {
+        check_pxpump_user(&env)?;
+
+        for upd_data in quotes.iter() {
+            set_quote(&env, &upd_data)?;
+        }
+        Ok(())
+    }
+
+    // Only users added by the admin can retrieve quotes, the caller has to
+    // authorize the call so nobody can retrieve quotes on a user's behalf.
+    pub fn retrieve(env: Env, caller: Address, instrument: Symbol) -> Result<UpdData, Error> {
+        if !is_initialized(&env) {
+            return Err(Error::NotInitialized);
+        }
+
+        caller.require_auth();
+        if !Self::is_user(env.clone(), caller.clone()) {
+            return Err(Error::Unauthorized);
+        }
+
+        let upd_data: UpdData = match env.storage().persistent().get(&DataKey::Quote(instrument)) {
+            Some(quote) => quote,
+            None => return Err(Error::NoQuote),
+        };
+        charge_fee(&env, &caller)?;
 
         let timestamp = env.ledger().timestamp();
 
         // Emit event
-        let topic = (Symbol::new( &env, "retrieve"), upd_data.token);
+        let topic = (Symbol::new(&env, "retrieve"), upd_data.instrument.clone());
         env.events().publish(topic, timestamp);
 
-        let mut ret_data: Vec<i128> = Vec::new(&env);
-        ret_data.push_back(upd_data.token);
-        ret_data.push_back(upd_data.price);
-        ret_data.push_back(upd_data.timestamp);
-        ret_data.push_back(upd_data.flags);
-        ret_data.push_back(upd_data.decimals as i128);
-        ret_data
+        Ok(upd_data)
     }
 }
 
@@ -101,4 +417,129 @@ fn is_initialized(env: &Env) -> bool {
     env.storage().instance().has(&DataKey::Init)
 }
 
+fn check_admin(env: &Env) -> Result<(), Error> {
+    if !is_initialized(env) {
+        return Err(Error::NotInitialized);
+    }
+    let admin: Address = env.storage().instance().get(&DataKey::Admin).unwrap();
+    admin.require_auth();
+    Ok(())
+}
+
+// Pay `amount` of the user's credit out to `to`, returns the credit left
+fn refund_credit(env: &Env, user: &Address, to: &Address, amount: i128) -> i128 {
+    let fee_token: Address = env.storage().instance().get(&DataKey::FeeToken).unwrap();
+    token::Client::new(env, &fee_token).transfer(&env.current_contract_address(), to, &amount);
+    let credit = OracleContract::credit_of(env.clone(), user.clone()) - amount;
+    env.storage().persistent().set(&DataKey::Credit(user.clone()), &credit);
+
+    let topic = (Symbol::new(env, "credit"), user.clone());
+    env.events().publish(topic, credit);
+    credit
+}
+
+// Take the fee of a retrieve from the caller's credit, the data fees are
+// passed through to the consumers this way
+fn charge_fee(env: &Env, caller: &Address) -> Result<(), Error> {
+    let fee: i128 = env.storage().instance().get(&DataKey::Fee).unwrap_or(0);
+    if fee == 0 {
+        return Ok(());
+    }
+    let key = DataKey::Credit(caller.clone());
+    let credit: i128 = env.storage().persistent().get(&key).unwrap_or(0);
+    if credit < fee {
+        return Err(Error::LowCredit);
+    }
+    env.storage().persistent().set(&key, &(credit - fee));
+    let fees: i128 = env.storage().instance().get(&DataKey::Fees).unwrap_or(0);
+    env.storage().instance().set(&DataKey::Fees, &(fees + fee));
+    Ok(())
+}
+
+fn get_quorum(env: &Env) -> u32 {
+    env.storage().instance().get(&DataKey::Quorum).unwrap_or(0)
+}
+
+fn get_npublishers(env: &Env) -> u32 {
+    env.storage().instance().get(&DataKey::NPublishers).unwrap_or(0)
+}
+
+fn get_submission(env: &Env, instrument: &Symbol, publisher: &Address) -> UpdData {
+    env.storage()
+        .persistent()
+        .get(&DataKey::Submission(instrument.clone(), publisher.clone()))
+        .unwrap()
+}
+
+// Quote of a round: the median price, the oldest timestamp so the quote
+// doesn't look fresher than it is, and only the flags all the publishers agree on
+fn aggregate(env: &Env, instrument: &Symbol, round: &Vec<Address>) -> UpdData {
+    let mut prices: Vec<i128> = Vec::new(env);
+    let mut timestamp = i128::MAX;
+    let mut flags: i128 = -1;
+    let mut decimals: u32 = 0;
+    for publisher in round.iter() {
+        let upd_data = get_submission(env, instrument, &publisher);
+        // Keep the prices sorted
+        let mut i = 0;
+        while i < prices.len() && prices.get_unchecked(i) < upd_data.price {
+            i += 1;
+        }
+        prices.insert(i, upd_data.price);
+        timestamp = timestamp.min(upd_data.timestamp);
+        flags &= upd_data.flags;
+        decimals = upd_data.decimals;
+    }
+    let mid = prices.len() / 2;
+    let price = if prices.len() % 2 == 1 {
+        prices.get_unchecked(mid)
+    } else {
+        (prices.get_unchecked(mid - 1) + prices.get_unchecked(mid)) / 2
+    };
+    UpdData {
+        instrument: instrument.clone(),
+        price,
+        timestamp,
+        flags,
+        decimals,
+    }
+}
+
+// Only the price pump user can push prices, and only while no quorum of
+// publishers is set
+fn check_pxpump_user(env: &Env) -> Result<(), Error> {
+    if !is_initialized(env) {
+        return Err(Error::NotInitialized);
+    }
+    if get_quorum(env) > 0 {
+        return Err(Error::Unauthorized);
+    }
+    let pxpump_user: Address = match env.storage().instance().get(&DataKey::PxPumpUser) {
+        Some(user) => user,
+        None => return Err(Error::Unauthorized),
+    };
+    pxpump_user.require_auth();
+    Ok(())
+}
+
+// Store the latest quote of an instrument, each instrument has its own entry
+fn set_quote(env: &Env, upd_data: &UpdData) -> Result<(), Error> {
+    if upd_data.decimals > MAX_DECIMALS {
+        return Err(Error::BadDecimals);
+    }
+    let key = DataKey::Quote(upd_data.instrument.clone());
+    // Prices can't go back in time
+    if let Some(quote) = env.storage().persistent().get::<_, UpdData>(&key) {
+        if upd_data.timestamp < quote.timestamp {
+            return Err(Error::StaleQuote);
+        }
+    }
+    env.storage().persistent().set(&key, upd_data);
+
+    // Emit event
+    let topic = (Symbol::new(env, "update"), upd_data.instrument.clone());
+    env.events().publish(topic, upd_data.timestamp);
+    Ok(())
+}
+
 mod test;
\ No newline at end of file
@@ -2,9 +2,12 @@
 
 extern crate std;
 
-use super::{OracleContract, OracleContractClient};
+use super::{Error, OracleContract, OracleContractClient, UpdData};
 
-use soroban_sdk::{testutils::Address as _, testutils::BytesN as _, Address, BytesN, Env};
+use soroban_sdk::{
+    symbol_short, testutils::Address as _, testutils::BytesN as _, token, vec, Address, BytesN,
+    Env,
+};
 
 #[test]
 fn test() {
@@ -15,7 +18,7 @@ fn test() {
     let client = OracleContractClient::new(&env, &contract_id);
 
     // Test init
-    client.init();
+    client.init(&Address::random(&env));
 
     // Test set_pxpump_user
     let user: Address = Address::random(&env);
@@ -26,10 +29,284 @@ fn test() {
     client.set_pxpump_hash(&hash);
 
     // Test update
-    let token: i128 = 0;
+    let instrument = symbol_short!("SPY");
     let price: i128 = 0;
     let timestamp: i128 = 0;
     let flags: i128 = 0;
     let decimals: u32 = 2;
-    client.update(&token, &price, &timestamp, &flags, &decimals);
+    client.update(&instrument, &price, &timestamp, &flags, &decimals);
+    // Test add_user
+    let consumer = Address::random(&env);
+    assert!(!client.is_user(&consumer));
+    client.add_user(&consumer);
+    assert!(client.is_user(&consumer));
+    let quote = client.retrieve(&consumer, &instrument);
+    assert_eq!(quote.instrument, instrument);
+    assert_eq!(quote.price, price);
+    assert_eq!(quote.timestamp, timestamp);
+    assert_eq!(quote.flags, flags);
+    assert_eq!(quote.decimals, decimals);
+}
+
+#[test]
+fn test_instruments() {
+    let env = Env::default();
+    env.mock_all_auths();
+
+    let contract_id = env.register_contract(None, OracleContract);
+    let client = OracleContractClient::new(&env, &contract_id);
+    client.init(&Address::random(&env));
+    client.set_pxpump_user(&Address::random(&env));
+    let user = Address::random(&env);
+    client.add_user(&user);
+
+    // Each instrument keeps its own quote
+    client.update(&symbol_short!("SPY"), &45000, &10, &4, &2);
+    client.update_many(&vec![
+        &env,
+        UpdData {
+            instrument: symbol_short!("QQQ"),
+            price: 37000,
+            timestamp: 20,
+            flags: 4,
+            decimals: 2,
+        },
+        UpdData {
+            instrument: symbol_short!("IWM"),
+            price: 18000,
+            timestamp: 20,
+            flags: 1,
+            decimals: 2,
+        },
+    ]);
+    let spy = client.retrieve(&user, &symbol_short!("SPY"));
+    assert_eq!((spy.price, spy.timestamp, spy.flags), (45000, 10, 4));
+    let qqq = client.retrieve(&user, &symbol_short!("QQQ"));
+    assert_eq!((qqq.price, qqq.timestamp, qqq.flags), (37000, 20, 4));
+    let iwm = client.retrieve(&user, &symbol_short!("IWM"));
+    assert_eq!((iwm.price, iwm.timestamp, iwm.flags), (18000, 20, 1));
+    assert_eq!(client.try_retrieve(&user, &symbol_short!("DIA")), Err(Ok(Error::NoQuote)));
+
+    // A stale quote fails the whole batch
+    let batch = vec![
+        &env,
+        UpdData {
+            instrument: symbol_short!("SPY"),
+            price: 45100,
+            timestamp: 30,
+            flags: 4,
+            decimals: 2,
+        },
+        UpdData {
+            instrument: symbol_short!("QQQ"),
+            price: 36900,
+            timestamp: 15,
+            flags: 4,
+            decimals: 2,
+        },
+    ];
+    assert_eq!(client.try_update_many(&batch), Err(Ok(Error::StaleQuote)));
+    let spy = client.retrieve(&user, &symbol_short!("SPY"));
+    assert_eq!((spy.price, spy.timestamp, spy.flags), (45000, 10, 4));
+}
+
+#[test]
+fn test_errors() {
+    let env = Env::default();
+    env.mock_all_auths();
+
+    let contract_id = env.register_contract(None, OracleContract);
+    let client = OracleContractClient::new(&env, &contract_id);
+
+    let spy = symbol_short!("SPY");
+    let user = Address::random(&env);
+    assert_eq!(client.try_retrieve(&user, &spy), Err(Ok(Error::NotInitialized)));
+    client.init(&Address::random(&env));
+    assert_eq!(client.try_init(&Address::random(&env)), Err(Ok(Error::AlreadyInitialized)));
+
+    // Only allowed users can retrieve quotes
+    assert_eq!(client.try_retrieve(&user, &spy), Err(Ok(Error::Unauthorized)));
+    client.add_user(&user);
+
+    // Nobody can push prices until the price pump user is set
+    assert_eq!(client.try_update(&spy, &100, &10, &4, &2), Err(Ok(Error::Unauthorized)));
+    assert_eq!(client.try_retrieve(&user, &spy), Err(Ok(Error::NoQuote)));
+
+    client.set_pxpump_user(&Address::random(&env));
+    assert_eq!(client.try_update(&spy, &100, &10, &4, &19), Err(Ok(Error::BadDecimals)));
+    client.update(&spy, &100, &10, &4, &2);
+    assert_eq!(client.try_update(&spy, &101, &9, &4, &2), Err(Ok(Error::StaleQuote)));
+    assert_eq!(client.retrieve(&user, &spy).price, 100);
+
+    client.remove_user(&user);
+    assert!(!client.is_user(&user));
+    assert_eq!(client.try_retrieve(&user, &spy), Err(Ok(Error::Unauthorized)));
+}
+
+#[test]
+fn test_fees() {
+    let env = Env::default();
+    env.mock_all_auths();
+
+    let contract_id = env.register_contract(None, OracleContract);
+    let client = OracleContractClient::new(&env, &contract_id);
+    client.init(&Address::random(&env));
+    client.set_pxpump_user(&Address::random(&env));
+    let spy = symbol_short!("SPY");
+    client.update(&spy, &45000, &10, &4, &2);
+    let user = Address::random(&env);
+    client.add_user(&user);
+
+    // Quotes are free until a fee is set
+    let payer = Address::random(&env);
+    assert_eq!(client.try_deposit_credit(&payer, &user, &10), Err(Ok(Error::NoFeeToken)));
+    client.retrieve(&user, &spy);
+
+    let token_admin = Address::random(&env);
+    let token_id = env.register_stellar_asset_contract(token_admin.clone());
+    let token = token::Client::new(&env, &token_id);
+    token::AdminClient::new(&env, &token_id).mint(&payer, &100);
+    assert_eq!(client.try_set_fee(&token_id, &-1), Err(Ok(Error::InvalidInput)));
+    client.set_fee(&token_id, &3);
+    let other_token = env.register_stellar_asset_contract(token_admin);
+    assert_eq!(client.try_set_fee(&other_token, &3), Err(Ok(Error::InvalidInput)));
+
+    // Each retrieve takes the fee from the user's credit
+    assert_eq!(client.try_retrieve(&user, &spy), Err(Ok(Error::LowCredit)));
+    assert_eq!(client.try_deposit_credit(&payer, &user, &0), Err(Ok(Error::InvalidInput)));
+    assert_eq!(client.try_deposit_credit(&payer, &user, &101), Err(Ok(Error::LowCredit)));
+    assert_eq!(client.deposit_credit(&payer, &user, &7), 7);
+    assert_eq!(token.balance(&payer), 93);
+    assert_eq!(token.balance(&contract_id), 7);
+    client.retrieve(&user, &spy);
+    client.retrieve(&user, &spy);
+    assert_eq!(client.credit_of(&user), 1);
+    assert_eq!(client.try_retrieve(&user, &spy), Err(Ok(Error::LowCredit)));
+
+    // Only the collected fees can be withdrawn, the credit stays
+    let treasury = Address::random(&env);
+    assert_eq!(client.withdraw_fees(&treasury), 6);
+    assert_eq!(client.withdraw_fees(&treasury), 0);
+    assert_eq!(token.balance(&treasury), 6);
+    assert_eq!(token.balance(&contract_id), 1);
+
+    // Unused credit is refunded on request or when the user is removed
+    client.deposit_credit(&payer, &user, &9);
+    assert_eq!(client.try_withdraw_credit(&user, &payer, &11), Err(Ok(Error::LowCredit)));
+    assert_eq!(client.try_withdraw_credit(&user, &payer, &0), Err(Ok(Error::InvalidInput)));
+    assert_eq!(client.withdraw_credit(&user, &payer, &4), 6);
+    assert_eq!(env.auths()[0].0, user);
+    assert_eq!(token.balance(&payer), 88);
+    client.remove_user(&user);
+    assert_eq!(client.credit_of(&user), 0);
+    assert_eq!(token.balance(&user), 6);
+    assert_eq!(token.balance(&contract_id), 0);
+}
+
+#[test]
+fn test_admin() {
+    let env = Env::default();
+    env.mock_all_auths();
+
+    let contract_id = env.register_contract(None, OracleContract);
+    let client = OracleContractClient::new(&env, &contract_id);
+    let admin = Address::random(&env);
+    assert_eq!(client.try_set_pxpump_user(&admin), Err(Ok(Error::NotInitialized)));
+    client.init(&admin);
+    assert_eq!(env.auths()[0].0, admin);
+    assert_eq!(client.admin(), admin);
+    assert_eq!(client.try_init(&admin), Err(Ok(Error::AlreadyInitialized)));
+
+    // Configuration changes need the admin's authorization
+    client.set_pxpump_user(&Address::random(&env));
+    assert_eq!(env.auths()[0].0, admin);
+    client.set_pxpump_hash(&BytesN::random(&env));
+    assert_eq!(env.auths()[0].0, admin);
+
+    // The admin role only moves once the new admin accepts it
+    assert_eq!(client.try_accept_admin(), Err(Ok(Error::Unauthorized)));
+    let new_admin = Address::random(&env);
+    client.propose_admin(&new_admin);
+    assert_eq!(env.auths()[0].0, admin);
+    assert_eq!(client.admin(), admin);
+    client.accept_admin();
+    assert_eq!(env.auths()[0].0, new_admin);
+    assert_eq!(client.admin(), new_admin);
+    assert_eq!(client.try_accept_admin(), Err(Ok(Error::Unauthorized)));
+
+    client.add_user(&Address::random(&env));
+    assert_eq!(env.auths()[0].0, new_admin);
+}
+
+#[test]
+fn test_publishers() {
+    let env = Env::default();
+    env.mock_all_auths();
+
+    let contract_id = env.register_contract(None, OracleContract);
+    let client = OracleContractClient::new(&env, &contract_id);
+    client.init(&Address::random(&env));
+    client.set_pxpump_user(&Address::random(&env));
+    let user = Address::random(&env);
+    client.add_user(&user);
+    let spy = symbol_short!("SPY");
+    let (exchange, vendor, backup) = (Address::random(&env), Address::random(&env), Address::random(&env));
+    client.add_publisher(&exchange);
+    client.add_publisher(&vendor);
+    client.add_publisher(&backup);
+
+    // Publishers can't submit until a quorum is set, then the price pump can't push
+    assert_eq!(client.try_submit(&exchange, &spy, &45000, &10, &4, &2), Err(Ok(Error::Unauthorized)));
+    client.update(&spy, &44000, &5, &4, &2);
+    client.set_quorum(&2);
+    assert_eq!(client.try_update(&spy, &44100, &6, &4, &2), Err(Ok(Error::Unauthorized)));
+    let stranger = Address::random(&env);
+    assert_eq!(client.try_submit(&stranger, &spy, &45000, &10, &4, &2), Err(Ok(Error::Unauthorized)));
+
+    // The quote is only published once the quorum is reached, resubmitting doesn't count twice
+    client.submit(&exchange, &spy, &45000, &10, &5, &2);
+    client.submit(&exchange, &spy, &45010, &11, &5, &2);
+    assert_eq!(client.retrieve(&user, &spy).price, 44000);
+    assert_eq!(client.try_submit(&vendor, &spy, &45100, &12, &4, &3), Err(Ok(Error::InvalidInput)));
+    assert_eq!(client.try_submit(&vendor, &spy, &45100, &4, &4, &2), Err(Ok(Error::StaleQuote)));
+    client.submit(&vendor, &spy, &45100, &12, &4, &2);
+    let quote = client.retrieve(&user, &spy);
+    assert_eq!((quote.price, quote.timestamp, quote.flags, quote.decimals), (45055, 11, 4, 2));
+
+    // Each publisher's last submission stays queryable
+    assert_eq!(client.submission(&exchange, &spy).price, 45010);
+    assert_eq!(client.submission(&vendor, &spy).price, 45100);
+    assert_eq!(client.try_submission(&backup, &spy), Err(Ok(Error::NoQuote)));
+
+    // Median of an odd round ignores an outlier
+    client.set_quorum(&3);
+    client.submit(&exchange, &spy, &45200, &20, &4, &2);
+    client.submit(&vendor, &spy, &99999, &21, &4, &2);
+    client.submit(&backup, &spy, &45220, &22, &4, &2);
+    let quote = client.retrieve(&user, &spy);
+    assert_eq!((quote.price, quote.timestamp), (45220, 20));
+
+    // A removed publisher's submission doesn't count toward the quorum
+    client.submit(&exchange, &spy, &45300, &30, &4, &2);
+    client.submit(&vendor, &spy, &45310, &30, &4, &2);
+    assert_eq!(client.try_remove_publisher(&vendor), Err(Ok(Error::InvalidInput)));
+    let spare = Address::random(&env);
+    client.add_publisher(&spare);
+    client.remove_publisher(&vendor);
+    assert_eq!(client.try_submit(&vendor, &spy, &45310, &31, &4, &2), Err(Ok(Error::Unauthorized)));
+    client.submit(&backup, &spy, &45320, &31, &4, &2);
+    assert_eq!(client.retrieve(&user, &spy).price, 45220);
+
+    // The quorum can't outnumber the publishers, adding one twice counts once
+    client.add_publisher(&exchange);
+    assert_eq!(client.try_set_quorum(&4), Err(Ok(Error::InvalidInput)));
+    assert_eq!(client.try_remove_publisher(&spare), Err(Ok(Error::InvalidInput)));
+
+    // Changing the quorum drops the round in progress
+    client.set_quorum(&2);
+    client.submit(&spare, &spy, &45400, &40, &4, &2);
+    assert_eq!(client.retrieve(&user, &spy).price, 45220);
+    client.submit(&exchange, &spy, &45500, &41, &4, &2);
+    let quote = client.retrieve(&user, &spy);
+    assert_eq!((quote.price, quote.timestamp), (45450, 40));
 }
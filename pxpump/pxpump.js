@@ -185,8 +185,10 @@ function toSorobanArgs(quote) {
       timestamp = quote.preMarketTime.getTime();
       break;
     case "POST":
-      flags |= 2;
-      price = quote.regularMarketPreviousClose;
+      // The regular session is over, its last price is the official close
+      // that the option contract settles against
+      flags |= 2 | 32;
+      price = quote.regularMarketPrice;
       timestamp = quote.regularMarketTime.getTime();
       break;
     case "REGULAR":
@@ -209,8 +211,8 @@ function toSorobanArgs(quote) {
   console.log(
     `Market state: ${marketState}, flags: ${flags}, price: ${price}, timestamp: ${timestamp}`
   );
-  // from the quote.  We'll just stringify the quote for now.
-  let symbolCode = SorobanClient.nativeToScVal(1, {type: "i128"});
+  // from the quote.  The oracle keeps a quote per instrument symbol.
+  let symbolCode = SorobanClient.nativeToScVal(quote.symbol, {type: "symbol"});
   console.dir(`Symbol code: ${symbolCode}`);
 
   // We know that Soroban VM doesn't support floating point numbers, so we'll
@@ -221,8 +223,9 @@ function toSorobanArgs(quote) {
   price = SorobanClient.nativeToScVal(Math.floor(price * 100), {type: "i128"});
   console.dir(`Price: ${price}`);
   // Dates are also not supported, so we'll convert them to timestamps in
-  // the same format as Soroban VM timestamps.
-  const ts = timestamp;
+  // the same format as Soroban VM timestamps, Unix seconds rather than the
+  // milliseconds of JS dates.
+  const ts = Math.floor(timestamp / 1000);
   console.log(`Timestamp: ${ts}`);
 
   timestamp =  SorobanClient.nativeToScVal(ts, {type: "i128"});